@@ -2,6 +2,7 @@ use std::{
     io::{IoSlice, IoSliceMut, Result},
     mem::zeroed,
     os::unix::prelude::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, RawFd},
+    time::Instant,
 };
 
 use crate::{
@@ -56,6 +57,11 @@ impl AnonPipe {
     pub fn read_to_end(&self, buf: &mut Vec<u8>) -> Result<usize> {
         self.0.read_to_end(buf)
     }
+
+    /// Duplicate this pipe to the lowest free descriptor number that is at least `min_fd`
+    pub fn duplicate_above(&self, min_fd: RawFd) -> Result<AnonPipe> {
+        Ok(AnonPipe(self.0.duplicate_above(min_fd)?))
+    }
 }
 
 impl AsRawFd for AnonPipe {
@@ -133,3 +139,74 @@ pub fn read2(p1: AnonPipe, v1: &mut Vec<u8>, p2: AnonPipe, v2: &mut Vec<u8>) ->
         }
     }
 }
+
+/// Like `read2`, but gives up at `deadline`, if there is one, and either pipe may be
+/// missing. Returns `true` if every pipe reached EOF, or `false` if the deadline passed
+/// first. The pipes are left in blocking mode either way, so they can still be used
+/// afterwards.
+pub fn read2_until(
+    p1: Option<&AnonPipe>,
+    v1: &mut Vec<u8>,
+    p2: Option<&AnonPipe>,
+    v2: &mut Vec<u8>,
+    deadline: Option<Instant>,
+) -> Result<bool> {
+    let pipes = [p1, p2];
+    for p in pipes.iter().flatten() {
+        p.set_nonblocking(true)?;
+    }
+    let result = read2_until_inner(pipes, [v1, v2], deadline);
+    for p in pipes.iter().flatten() {
+        p.set_nonblocking(false)?;
+    }
+    result
+}
+
+fn read2_until_inner(
+    pipes: [Option<&AnonPipe>; 2],
+    mut vs: [&mut Vec<u8>; 2],
+    deadline: Option<Instant>,
+) -> Result<bool> {
+    let mut fds: [libc::pollfd; 2] = unsafe { zeroed() };
+    for (fd, p) in fds.iter_mut().zip(pipes) {
+        // poll ignores negative fds, so missing pipes and pipes at EOF are skipped
+        fd.fd = p.map_or(-1, |p| p.as_raw_fd());
+        fd.events = libc::POLLIN;
+    }
+
+    loop {
+        if fds.iter().all(|fd| fd.fd < 0) {
+            return Ok(true);
+        }
+        // Without a deadline, poll blocks until a pipe is ready
+        let millis = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Ok(false);
+                }
+                // Round up, so we don't wake up just before the deadline and spin
+                remaining
+                    .as_nanos()
+                    .div_ceil(1_000_000)
+                    .min(libc::c_int::MAX as u128) as libc::c_int
+            }
+            None => -1,
+        };
+        cvt_r(|| unsafe { libc::poll(fds.as_mut_ptr(), 2, millis) })?;
+
+        for ((fd, p), v) in fds.iter_mut().zip(pipes).zip(vs.iter_mut()) {
+            if fd.fd < 0 || fd.revents == 0 {
+                continue;
+            }
+            let p = p.expect("polled a missing pipe");
+            match p.read_to_end(v) {
+                Ok(_) => fd.fd = -1,
+                Err(e)
+                    if e.raw_os_error() == Some(libc::EWOULDBLOCK)
+                        || e.raw_os_error() == Some(libc::EAGAIN) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::io::{Error, ErrorKind, IoSlice, IoSliceMut, Read, Result, Write};
+use std::time::{Duration, Instant};
 
-use crate::anon_pipe::{read2, AnonPipe};
+use crate::anon_pipe::{read2, read2_until, AnonPipe};
+use crate::error::OutputTimeout;
 use crate::output::Output;
-use crate::process::{ExitStatus, Process};
+use crate::process::{no_pidfd, ExitStatus, PidFd, Process};
 use crate::stdio::StdioPipes;
 
 /// A child process created from a `MemFdExecutable` with handles to input and output streams
@@ -15,12 +17,15 @@ pub struct Child {
     pub stdout: Option<ChildStdout>,
     /// The error stream from the child process
     pub stderr: Option<ChildStderr>,
+    /// Whether to kill and reap the child process when this handle is dropped
+    kill_on_drop: bool,
 }
 
 impl Child {
     pub fn new(handle: Process, stdio: StdioPipes) -> Self {
         Self {
             handle,
+            kill_on_drop: false,
             stdin: stdio.stdin.map(ChildStdin),
             stdout: stdio.stdout.map(ChildStdout),
             stderr: stdio.stderr.map(ChildStderr),
@@ -32,11 +37,106 @@ impl Child {
         self.handle.kill()
     }
 
+    /// Send a signal, like `libc::SIGTERM`, to the child process. This fails if the child
+    /// has already been waited on, since its pid may belong to another process by then.
+    pub fn signal(&mut self, signal: i32) -> Result<()> {
+        self.handle.signal(signal)
+    }
+
+    /// Send a signal, like `libc::SIGKILL`, to every process in the child's process group,
+    /// including any processes it has forked. The child must have been spawned in a process
+    /// group with `MemFdExecutable::process_group` or `MemFdExecutable::setsid`, otherwise
+    /// this fails with `ErrorKind::InvalidInput` rather than signal the group the child
+    /// shares with its parent. Like `signal`, it also fails once the child has been waited
+    /// on, since the group id may belong to another group by then.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::fs::read;
+    ///
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let mut sh = MemFdExecutable::new("sh", &read("/bin/sh").unwrap())
+    ///     .arg("-c")
+    ///     .arg("sleep 60 & sleep 60")
+    ///     .process_group(0)
+    ///     .spawn()
+    ///     .expect("failed to spawn sh");
+    ///
+    /// // Kills both sleeps as well as the shell
+    /// sh.kill_group(libc::SIGKILL).unwrap();
+    /// assert_eq!(sh.wait().unwrap().signal(), Some(libc::SIGKILL));
+    /// ```
+    pub fn kill_group(&mut self, signal: i32) -> Result<()> {
+        self.handle.kill_group(signal)
+    }
+
+    /// Terminate the child process gracefully. This sends `SIGTERM`, waits up to `grace`
+    /// for the child to exit, and then sends `SIGKILL` if it hasn't. The child is reaped,
+    /// and its exit status is returned. If the child has already exited, its exit status
+    /// is returned without sending any signals.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::fs::read;
+    /// use std::time::Duration;
+    ///
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let mut sleep = MemFdExecutable::new("sleep", &read("/bin/sleep").unwrap())
+    ///     .arg("60")
+    ///     .spawn()
+    ///     .expect("failed to spawn sleep");
+    ///
+    /// let status = sleep.terminate(Duration::from_secs(5)).unwrap();
+    /// assert_eq!(status.signal(), Some(libc::SIGTERM));
+    /// ```
+    pub fn terminate(&mut self, grace: Duration) -> Result<ExitStatus> {
+        drop(self.stdin.take());
+        self.handle.terminate(grace)
+    }
+
     /// Return the id of the child process, probably a PID
     pub fn id(&self) -> u32 {
         self.handle.id()
     }
 
+    /// Set whether the child process is killed with `SIGKILL` and reaped when this handle is
+    /// dropped, if it is still running. By default, like `std::process::Child`, dropping
+    /// the handle leaves the child running.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::fs::read;
+    /// use std::path::Path;
+    ///
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let mut sleep = MemFdExecutable::new("sleep", &read("/bin/sleep").unwrap())
+    ///     .arg("60")
+    ///     .spawn()
+    ///     .expect("failed to spawn sleep");
+    /// sleep.kill_on_drop(true);
+    ///
+    /// let proc = format!("/proc/{}", sleep.id());
+    /// drop(sleep);
+    /// assert!(!Path::new(&proc).exists());
+    /// ```
+    pub fn kill_on_drop(&mut self, kill_on_drop: bool) {
+        self.kill_on_drop = kill_on_drop;
+    }
+
+    /// Return the pidfd of the child process. It implements `AsFd`, and becomes readable
+    /// when the child exits, so it can be polled alongside other file descriptors. This
+    /// fails with `ErrorKind::Unsupported` on kernels without full pidfd support (before
+    /// 5.4).
+    pub fn pidfd(&self) -> Result<&PidFd> {
+        self.handle.pidfd().ok_or_else(no_pidfd)
+    }
+
     /// Wait for the child process to exit, returning the exit status code
     pub fn wait(&mut self) -> Result<ExitStatus> {
         drop(self.stdin.take());
@@ -48,6 +148,81 @@ impl Child {
         self.handle.try_wait()
     }
 
+    /// Wait for the child process to exit for at most `timeout`, returning the exit status
+    /// code if it has, or `None` if it is still running. This sleeps until the child exits
+    /// or the timeout passes, rather than repeatedly calling `try_wait`. A timeout too large
+    /// to have a deadline, like `Duration::MAX`, waits until the child exits.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<ExitStatus>> {
+        drop(self.stdin.take());
+        self.handle.wait_timeout(timeout)
+    }
+
+    /// Wait for the child process to exit for at most `timeout`, returning the exit status
+    /// code and the output streams. If the child is still running when the timeout passes,
+    /// this returns an `ErrorKind::TimedOut` error holding an `OutputTimeout` with the
+    /// output read so far. The child keeps running, and its `stdout` and `stderr` are left
+    /// in place, so it can be waited on again or killed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::fs::read;
+    /// use std::io::ErrorKind;
+    /// use std::time::Duration;
+    ///
+    /// use memfd_exec::{MemFdExecutable, OutputTimeout, Stdio};
+    ///
+    /// let mut sh = MemFdExecutable::new("sh", &read("/bin/sh").unwrap())
+    ///     .arg("-c")
+    ///     .arg("echo started; exec sleep 60")
+    ///     .stdout(Stdio::piped())
+    ///     .spawn()
+    ///     .expect("failed to spawn sh");
+    ///
+    /// let err = sh.wait_with_output_timeout(Duration::from_millis(500)).unwrap_err();
+    /// assert_eq!(err.kind(), ErrorKind::TimedOut);
+    /// let partial = err.into_inner().unwrap().downcast::<OutputTimeout>().unwrap();
+    /// assert_eq!(partial.stdout, b"started\n");
+    ///
+    /// sh.kill().unwrap();
+    /// sh.wait().unwrap();
+    /// ```
+    pub fn wait_with_output_timeout(&mut self, timeout: Duration) -> Result<Output> {
+        drop(self.stdin.take());
+        // A timeout too large to have a deadline, like `Duration::MAX`, never runs out
+        let deadline = Instant::now().checked_add(timeout);
+
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        let eof = read2_until(
+            self.stdout.as_ref().map(|out| &out.0),
+            &mut stdout,
+            self.stderr.as_ref().map(|err| &err.0),
+            &mut stderr,
+            deadline,
+        )?;
+
+        let status = match deadline {
+            _ if !eof => None,
+            Some(deadline) => self
+                .handle
+                .wait_timeout(deadline.saturating_duration_since(Instant::now()))?,
+            None => Some(self.handle.wait()?),
+        };
+
+        match status {
+            Some(status) => {
+                drop(self.stdout.take());
+                drop(self.stderr.take());
+                Ok(Output {
+                    status,
+                    stdout,
+                    stderr,
+                })
+            }
+            None => Err(OutputTimeout { stdout, stderr }.into()),
+        }
+    }
+
     /// Wait for the child process to exit, returning the exit status code and the output
     /// streams
     pub fn wait_with_output(mut self) -> Result<Output> {
@@ -75,8 +250,19 @@ impl Child {
     }
 }
 
+impl Drop for Child {
+    fn drop(&mut self) {
+        if self.kill_on_drop && matches!(self.handle.try_wait(), Ok(None)) {
+            // There's nothing useful to do with errors here. If the kill fails, the child
+            // has exited in the meantime and still needs to be reaped.
+            let _ = self.handle.kill();
+            let _ = self.handle.wait();
+        }
+    }
+}
+
 /// A handle to a child process’s standard input (stdin).
-pub struct ChildStdin(AnonPipe);
+pub struct ChildStdin(pub(crate) AnonPipe);
 
 impl std::os::fd::AsRawFd for ChildStdin {
     #[inline]
@@ -141,7 +327,7 @@ impl Debug for ChildStdin {
 }
 
 /// A handle to a child process’s standard output (stdout).
-pub struct ChildStdout(AnonPipe);
+pub struct ChildStdout(pub(crate) AnonPipe);
 
 impl std::os::fd::AsRawFd for ChildStdout {
     #[inline]
@@ -234,7 +420,7 @@ impl Debug for ChildStdout {
 }
 
 /// A handle to a child process’s stderr.
-pub struct ChildStderr(AnonPipe);
+pub struct ChildStderr(pub(crate) AnonPipe);
 
 impl std::os::fd::AsRawFd for ChildStderr {
     #[inline]
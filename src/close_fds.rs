@@ -0,0 +1,132 @@
+//! Closing the descriptors a program shouldn't inherit from its parent. This runs in the
+//! child between `fork` and `exec`, so it only makes raw system calls and doesn't allocate.
+
+use std::ffi::CStr;
+use std::io::{Error, Result};
+use std::os::unix::prelude::RawFd;
+
+use crate::cvt::{cvt, cvt_r};
+
+const PROC_SELF_FD: &CStr = c"/proc/self/fd";
+
+/// Offsets of the fields we need in a `struct linux_dirent64`
+const DIRENT_RECLEN: usize = 16;
+const DIRENT_NAME: usize = 19;
+
+/// Close every descriptor above stderr except the ones in `keep`, which must be sorted and
+/// below `cloexec_from`. Descriptors from `cloexec_from` up are marked close-on-exec
+/// instead, so the ones the child still needs until it execs stay usable until then.
+///
+/// This uses `close_range` (Linux 5.11), and falls back to walking `/proc/self/fd` on
+/// older kernels.
+pub(crate) unsafe fn close_other_fds<I>(keep: I, cloexec_from: RawFd) -> Result<()>
+where
+    I: Iterator<Item = RawFd> + Clone,
+{
+    match close_range_except(keep.clone(), cloexec_from) {
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS) | Some(libc::EINVAL)) => {
+            close_proc_fds_except(keep, cloexec_from)
+        }
+        result => result,
+    }
+}
+
+unsafe fn close_range(first: RawFd, last: RawFd, flags: libc::c_uint) -> Result<()> {
+    cvt(libc::syscall(
+        libc::SYS_close_range,
+        first as libc::c_uint,
+        last as libc::c_uint,
+        flags,
+    ))
+    .map(drop)
+}
+
+unsafe fn close_range_except<I>(keep: I, cloexec_from: RawFd) -> Result<()>
+where
+    I: Iterator<Item = RawFd>,
+{
+    // Close the gaps between the descriptors we keep
+    let mut first = libc::STDERR_FILENO + 1;
+    for fd in keep {
+        if fd > first {
+            close_range(first, fd - 1, 0)?;
+        }
+        first = fd + 1;
+    }
+    if cloexec_from > first {
+        close_range(first, cloexec_from - 1, 0)?;
+    }
+    close_range(cloexec_from, RawFd::MAX, libc::CLOSE_RANGE_CLOEXEC)
+}
+
+unsafe fn close_proc_fds_except<I>(keep: I, cloexec_from: RawFd) -> Result<()>
+where
+    I: Iterator<Item = RawFd> + Clone,
+{
+    let dir = cvt_r(|| {
+        libc::open(
+            PROC_SELF_FD.as_ptr(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    })?;
+    let result = for_each_proc_fd(dir, |fd| {
+        if fd <= libc::STDERR_FILENO || fd == dir || keep.clone().any(|k| k == fd) {
+            Ok(())
+        } else if fd >= cloexec_from {
+            cvt(libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC)).map(drop)
+        } else {
+            // Closing can't fail in a way that leaves the descriptor open on Linux
+            libc::close(fd);
+            Ok(())
+        }
+    });
+    libc::close(dir);
+    result
+}
+
+/// Call `f` with each descriptor listed in the open `/proc/self/fd` directory `dir`
+unsafe fn for_each_proc_fd<F>(dir: RawFd, mut f: F) -> Result<()>
+where
+    F: FnMut(RawFd) -> Result<()>,
+{
+    let mut buf = [0u8; 1024];
+    loop {
+        let n = cvt(libc::syscall(
+            libc::SYS_getdents64,
+            dir,
+            buf.as_mut_ptr(),
+            buf.len(),
+        ))? as usize;
+        if n == 0 {
+            return Ok(());
+        }
+        let mut offset = 0;
+        while offset < n {
+            let reclen =
+                u16::from_ne_bytes([buf[offset + DIRENT_RECLEN], buf[offset + DIRENT_RECLEN + 1]])
+                    as usize;
+            if reclen == 0 || offset + reclen > n {
+                return Err(Error::from_raw_os_error(libc::EIO));
+            }
+            if let Some(fd) = parse_fd(&buf[offset + DIRENT_NAME..offset + reclen]) {
+                f(fd)?;
+            }
+            offset += reclen;
+        }
+    }
+}
+
+/// Parse a NUL-terminated directory entry name as a descriptor number, skipping `.` and
+/// `..`
+fn parse_fd(name: &[u8]) -> Option<RawFd> {
+    let mut fd: RawFd = 0;
+    let mut digits = 0;
+    for &c in name.iter().take_while(|&&c| c != 0) {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        fd = fd.checked_mul(10)?.checked_add((c - b'0') as RawFd)?;
+        digits += 1;
+    }
+    (digits > 0).then_some(fd)
+}
@@ -0,0 +1,132 @@
+//! The code of a program, which a `MemFdExecutable` can borrow from the parent, for example
+//! when it is embedded with `include_bytes!()`, or own, so that the executable is `'static`.
+
+use std::{
+    borrow::Cow,
+    fmt::{Debug, Formatter, Result as FmtResult},
+    ops::Deref,
+    sync::Arc,
+};
+
+/// The code of an executable, a shared library or a dynamic loader. This converts from
+/// borrowed byte slices, arrays and `Vec<u8>`s, from owned `Vec<u8>`s, `Box<[u8]>`s and
+/// `Arc<[u8]>`s, and with the `bytes` feature, from `bytes::Bytes`.
+///
+/// A `MemFdExecutable` built from owned code isn't tied to a lifetime, so it can be kept in
+/// a struct or sent to another thread.
+///
+/// # Examples
+///
+/// ```
+/// use std::fs::read;
+/// use std::thread::spawn;
+///
+/// use memfd_exec::{MemFdExecutable, Stdio};
+///
+/// let mut echo = MemFdExecutable::new("echo", read("/bin/echo").unwrap());
+/// echo.arg("hello").stdout(Stdio::piped());
+///
+/// let output = spawn(move || echo.output().expect("failed to run echo"))
+///     .join()
+///     .unwrap();
+/// assert_eq!(output.stdout, b"hello\n");
+/// ```
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum Code<'a> {
+    /// Code borrowed from somewhere else
+    Borrowed(&'a [u8]),
+    /// Code owned by the executable
+    Owned(Vec<u8>),
+    /// Code shared with other owners
+    Shared(Arc<[u8]>),
+    /// Code in a `bytes::Bytes` buffer
+    #[cfg(feature = "bytes")]
+    Bytes(bytes::Bytes),
+}
+
+impl Deref for Code<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Code::Borrowed(code) => code,
+            Code::Owned(code) => code,
+            Code::Shared(code) => code,
+            #[cfg(feature = "bytes")]
+            Code::Bytes(code) => code,
+        }
+    }
+}
+
+impl AsRef<[u8]> for Code<'_> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Debug for Code<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        // Code is usually far too big to print
+        let kind = match self {
+            Code::Borrowed(_) => "Borrowed",
+            Code::Owned(_) => "Owned",
+            Code::Shared(_) => "Shared",
+            #[cfg(feature = "bytes")]
+            Code::Bytes(_) => "Bytes",
+        };
+        write!(f, "{}([{} bytes])", kind, self.len())
+    }
+}
+
+impl<'a> From<&'a [u8]> for Code<'a> {
+    fn from(code: &'a [u8]) -> Self {
+        Code::Borrowed(code)
+    }
+}
+
+impl<'a, const N: usize> From<&'a [u8; N]> for Code<'a> {
+    fn from(code: &'a [u8; N]) -> Self {
+        Code::Borrowed(code)
+    }
+}
+
+impl<'a> From<&'a Vec<u8>> for Code<'a> {
+    fn from(code: &'a Vec<u8>) -> Self {
+        Code::Borrowed(code)
+    }
+}
+
+impl From<Vec<u8>> for Code<'_> {
+    fn from(code: Vec<u8>) -> Self {
+        Code::Owned(code)
+    }
+}
+
+impl From<Box<[u8]>> for Code<'_> {
+    fn from(code: Box<[u8]>) -> Self {
+        Code::Owned(code.into_vec())
+    }
+}
+
+impl<'a> From<Cow<'a, [u8]>> for Code<'a> {
+    fn from(code: Cow<'a, [u8]>) -> Self {
+        match code {
+            Cow::Borrowed(code) => Code::Borrowed(code),
+            Cow::Owned(code) => Code::Owned(code),
+        }
+    }
+}
+
+impl From<Arc<[u8]>> for Code<'_> {
+    fn from(code: Arc<[u8]>) -> Self {
+        Code::Shared(code)
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl From<bytes::Bytes> for Code<'_> {
+    fn from(code: bytes::Bytes) -> Self {
+        Code::Bytes(code)
+    }
+}
@@ -4,6 +4,20 @@ use std::collections::BTreeMap;
 use std::env;
 use std::ffi::{OsStr, OsString};
 
+/// The environment a child process starts from, before any changes made with
+/// `MemFdExecutable::env` and friends are applied
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum EnvPolicy {
+    /// Start from the parent's environment. This is the default, and matches
+    /// `process::Command`
+    #[default]
+    Inherit,
+    /// Start from an empty environment
+    Clear,
+    /// Start from only the listed variables of the parent's environment
+    Allowlist(Vec<OsString>),
+}
+
 // Stores a set of changes to an environment
 #[derive(Clone, Debug, Default)]
 pub struct CommandEnv {
@@ -15,9 +29,17 @@ pub struct CommandEnv {
 impl CommandEnv {
     // Capture the current environment with these changes applied
     pub fn capture(&self) -> BTreeMap<OsString, OsString> {
+        self.capture_from(env::vars_os())
+    }
+
+    // Capture the given base environment with these changes applied
+    pub fn capture_from<I>(&self, base: I) -> BTreeMap<OsString, OsString>
+    where
+        I: IntoIterator<Item = (OsString, OsString)>,
+    {
         let mut result = BTreeMap::<OsString, OsString>::new();
         if !self.clear {
-            for (k, v) in env::vars_os() {
+            for (k, v) in base {
                 result.insert(k, v);
             }
         }
@@ -34,18 +56,6 @@ impl CommandEnv {
         result
     }
 
-    pub fn is_unchanged(&self) -> bool {
-        !self.clear && self.vars.is_empty()
-    }
-
-    pub fn capture_if_changed(&self) -> Option<BTreeMap<OsString, OsString>> {
-        if self.is_unchanged() {
-            None
-        } else {
-            Some(self.capture())
-        }
-    }
-
     // The following functions build up changes
     pub fn set(&mut self, key: &OsStr, value: &OsStr) {
         let key = OsString::from(key);
@@ -79,6 +89,7 @@ impl CommandEnv {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct CommandEnvs<'a> {
     iter: std::collections::btree_map::Iter<'a, OsString, Option<OsString>>,
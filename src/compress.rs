@@ -0,0 +1,65 @@
+//! Decompressing code straight into a memfd, for programs that are embedded compressed so
+//! they don't take up their full size in the parent's binary. Each format is behind a cargo
+//! feature: `zstd`, `xz` and `gzip`.
+
+use std::io::{Error, ErrorKind, Read, Result};
+
+/// A compression format, recognized by the magic bytes compressed data starts with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Zstd,
+    Xz,
+    Gzip,
+}
+
+impl Format {
+    const ALL: [Format; 3] = [Format::Zstd, Format::Xz, Format::Gzip];
+
+    fn magic(self) -> &'static [u8] {
+        match self {
+            Format::Zstd => b"\x28\xb5\x2f\xfd",
+            Format::Xz => b"\xfd7zXZ\x00",
+            Format::Gzip => b"\x1f\x8b",
+        }
+    }
+
+    /// The cargo feature that enables decompressing the format
+    fn feature(self) -> &'static str {
+        match self {
+            Format::Zstd => "zstd",
+            Format::Xz => "xz",
+            Format::Gzip => "gzip",
+        }
+    }
+
+    fn detect(code: &[u8]) -> Option<Format> {
+        Format::ALL
+            .into_iter()
+            .find(|format| code.starts_with(format.magic()))
+    }
+}
+
+/// Get a reader of the decompressed `code`. The format is detected from the magic bytes
+/// `code` starts with, and code that isn't in any known format is read as it is. Code in a
+/// format whose feature isn't enabled is an `ErrorKind::Unsupported` error.
+pub(crate) fn decoder<'a>(code: &'a [u8]) -> Result<Box<dyn Read + 'a>> {
+    // Concatenated streams are decompressed one after another, like the command line tools do
+    match Format::detect(code) {
+        None => Ok(Box::new(code)),
+        #[cfg(feature = "zstd")]
+        Some(Format::Zstd) => Ok(Box::new(zstd::Decoder::with_buffer(code)?)),
+        #[cfg(feature = "xz")]
+        Some(Format::Xz) => Ok(Box::new(xz2::read::XzDecoder::new_multi_decoder(code))),
+        #[cfg(feature = "gzip")]
+        Some(Format::Gzip) => Ok(Box::new(flate2::read::MultiGzDecoder::new(code))),
+        #[allow(unreachable_patterns)]
+        Some(format) => Err(Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "code is {} compressed, but the `{}` feature is not enabled",
+                format.feature(),
+                format.feature()
+            ),
+        )),
+    }
+}
@@ -0,0 +1,404 @@
+//! Checks that code looks like an ELF executable the host can run, before `spawn` forks.
+//! The kernel only reports `ENOEXEC` for most of these problems, and some of them, like a
+//! truncated segment, only show up as a crash once the program is running. This also reads
+//! the dynamic linking information `MemFdExecutable::inspect` reports on.
+
+use std::io::Result;
+use std::mem::size_of;
+
+use crate::error::InvalidExecutable;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const EI_NIDENT: usize = 16;
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+
+pub(crate) const ELFCLASS32: u8 = 1;
+pub(crate) const ELFCLASS64: u8 = 2;
+pub(crate) const ELFDATA2LSB: u8 = 1;
+pub(crate) const ELFDATA2MSB: u8 = 2;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+const EM_386: u16 = 3;
+const EM_MIPS: u16 = 8;
+const EM_PPC: u16 = 20;
+const EM_PPC64: u16 = 21;
+const EM_S390: u16 = 22;
+const EM_ARM: u16 = 40;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+const EM_RISCV: u16 = 243;
+const EM_LOONGARCH: u16 = 258;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_STRSZ: u64 = 10;
+const DT_SONAME: u64 = 14;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+
+#[cfg(target_pointer_width = "64")]
+const HOST_CLASS: u8 = ELFCLASS64;
+#[cfg(target_pointer_width = "32")]
+const HOST_CLASS: u8 = ELFCLASS32;
+
+#[cfg(target_endian = "little")]
+const HOST_DATA: u8 = ELFDATA2LSB;
+#[cfg(target_endian = "big")]
+const HOST_DATA: u8 = ELFDATA2MSB;
+
+/// The `e_machine` of programs the host runs natively, if we know it
+const HOST_MACHINE: Option<u16> = if cfg!(target_arch = "x86_64") {
+    Some(EM_X86_64)
+} else if cfg!(target_arch = "x86") {
+    Some(EM_386)
+} else if cfg!(target_arch = "aarch64") {
+    Some(EM_AARCH64)
+} else if cfg!(target_arch = "arm") {
+    Some(EM_ARM)
+} else if cfg!(target_arch = "riscv64") || cfg!(target_arch = "riscv32") {
+    Some(EM_RISCV)
+} else if cfg!(target_arch = "powerpc64") {
+    Some(EM_PPC64)
+} else if cfg!(target_arch = "powerpc") {
+    Some(EM_PPC)
+} else if cfg!(target_arch = "s390x") {
+    Some(EM_S390)
+} else if cfg!(target_arch = "loongarch64") {
+    Some(EM_LOONGARCH)
+} else if cfg!(target_arch = "mips") || cfg!(target_arch = "mips64") {
+    Some(EM_MIPS)
+} else {
+    None
+};
+
+/// The class and machine of an ELF file, which together say which programs it can run as
+/// or be loaded into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Target {
+    pub class: u8,
+    pub machine: u16,
+}
+
+/// The targets the kernel runs in compat mode on the host, besides its native one: i386 and
+/// x32 programs on x86_64, and 32-bit ARM programs on aarch64
+const COMPAT_TARGETS: &[Target] = if cfg!(target_arch = "x86_64") {
+    &[
+        Target {
+            class: ELFCLASS32,
+            machine: EM_386,
+        },
+        Target {
+            class: ELFCLASS32,
+            machine: EM_X86_64,
+        },
+    ]
+} else if cfg!(target_arch = "aarch64") {
+    &[Target {
+        class: ELFCLASS32,
+        machine: EM_ARM,
+    }]
+} else {
+    &[]
+};
+
+/// Where the fields we check are in the ELF header and program headers of each class
+struct Layout {
+    ehdr_size: u64,
+    phoff: (usize, usize),
+    phentsize: usize,
+    phnum: usize,
+    phdr_size: u64,
+    p_offset: (usize, usize),
+    p_vaddr: (usize, usize),
+    p_filesz: (usize, usize),
+    /// Size of a dynamic entry, which is a tag followed by a value of the same size
+    dyn_size: usize,
+}
+
+const LAYOUT_32: Layout = Layout {
+    ehdr_size: 52,
+    phoff: (28, 4),
+    phentsize: 42,
+    phnum: 44,
+    phdr_size: 32,
+    p_offset: (4, 4),
+    p_vaddr: (8, 4),
+    p_filesz: (16, 4),
+    dyn_size: 8,
+};
+
+const LAYOUT_64: Layout = Layout {
+    ehdr_size: 64,
+    phoff: (32, 8),
+    phentsize: 54,
+    phnum: 56,
+    phdr_size: 56,
+    p_offset: (8, 8),
+    p_vaddr: (16, 8),
+    p_filesz: (32, 8),
+    dyn_size: 16,
+};
+
+/// The layout of an ELF file of `class`, which is `ELFCLASS32` or `ELFCLASS64`
+fn layout(class: u8) -> &'static Layout {
+    match class {
+        ELFCLASS32 => &LAYOUT_32,
+        _ => &LAYOUT_64,
+    }
+}
+
+/// Read a native-endian integer of `size` bytes at `offset` in `buf`
+fn read_uint(buf: &[u8], (offset, size): (usize, usize)) -> u64 {
+    match size {
+        4 => u32::from_ne_bytes(buf[offset..offset + 4].try_into().unwrap()) as u64,
+        8 => u64::from_ne_bytes(buf[offset..offset + 8].try_into().unwrap()),
+        _ => unreachable!("ELF fields are 4 or 8 bytes"),
+    }
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([buf[offset], buf[offset + 1]])
+}
+
+/// Check that the `len` bytes of code read by `read_at` are an ELF executable the host can
+/// run, natively or in compat mode, and return its target. `read_at` fills the buffer it is
+/// given from the given offset, which is always in bounds. Problems with the code are
+/// returned as an `InvalidExecutable` in an `io::Error`.
+pub(crate) fn validate<R>(len: u64, mut read_at: R) -> Result<Target>
+where
+    R: FnMut(u64, &mut [u8]) -> Result<()>,
+{
+    let truncated = |needed| InvalidExecutable::Truncated { len, needed };
+
+    let mut ehdr = [0; LAYOUT_64.ehdr_size as usize];
+    if len < ELF_MAGIC.len() as u64 {
+        return Err(InvalidExecutable::NotElf.into());
+    }
+    read_at(0, &mut ehdr[..ELF_MAGIC.len()])?;
+    if &ehdr[..ELF_MAGIC.len()] != ELF_MAGIC {
+        return Err(InvalidExecutable::NotElf.into());
+    }
+    if len < EI_NIDENT as u64 {
+        return Err(truncated(EI_NIDENT as u64).into());
+    }
+    read_at(0, &mut ehdr[..EI_NIDENT])?;
+
+    let class = ehdr[EI_CLASS];
+    if class != HOST_CLASS && !COMPAT_TARGETS.iter().any(|target| target.class == class) {
+        return Err(InvalidExecutable::WrongClass {
+            expected: HOST_CLASS,
+            found: class,
+        }
+        .into());
+    }
+    let data = ehdr[EI_DATA];
+    if data != HOST_DATA {
+        return Err(InvalidExecutable::WrongEndianness {
+            expected: HOST_DATA,
+            found: data,
+        }
+        .into());
+    }
+
+    // The class is one the host runs, and everything is in the host's byte order from here
+    let layout = layout(class);
+    if len < layout.ehdr_size {
+        return Err(truncated(layout.ehdr_size).into());
+    }
+    let ehdr = &mut ehdr[..layout.ehdr_size as usize];
+    read_at(0, ehdr)?;
+
+    let e_type = read_u16(ehdr, 16);
+    if e_type != ET_EXEC && e_type != ET_DYN {
+        return Err(InvalidExecutable::NotExecutable { e_type }.into());
+    }
+    let machine = read_u16(ehdr, 18);
+    let target = Target { class, machine };
+    if let Some(expected) = HOST_MACHINE {
+        let native = Target {
+            class: HOST_CLASS,
+            machine: expected,
+        };
+        if target != native && !COMPAT_TARGETS.contains(&target) {
+            return Err(InvalidExecutable::WrongMachine {
+                expected,
+                found: machine,
+            }
+            .into());
+        }
+    }
+
+    let phoff = read_uint(ehdr, layout.phoff);
+    let phentsize = read_u16(ehdr, layout.phentsize) as u64;
+    let phnum = read_u16(ehdr, layout.phnum) as u64;
+    if phnum == 0 || phentsize != layout.phdr_size {
+        return Err(InvalidExecutable::BadProgramHeaders.into());
+    }
+    match phnum
+        .checked_mul(phentsize)
+        .and_then(|size| size.checked_add(phoff))
+    {
+        Some(end) if end <= len => {}
+        Some(end) => return Err(truncated(end).into()),
+        None => return Err(InvalidExecutable::BadProgramHeaders.into()),
+    }
+
+    let mut phdr = [0; LAYOUT_64.phdr_size as usize];
+    let phdr = &mut phdr[..layout.phdr_size as usize];
+    for index in 0..phnum {
+        read_at(phoff + index * phentsize, phdr)?;
+        let offset = read_uint(phdr, layout.p_offset);
+        let size = read_uint(phdr, layout.p_filesz);
+        // Segments without any file contents, like the stack, can have any offset
+        if size != 0 && offset.checked_add(size).is_none_or(|end| end > len) {
+            return Err(InvalidExecutable::SegmentOutOfBounds {
+                index: index as usize,
+                offset,
+                size,
+                len,
+            }
+            .into());
+        }
+    }
+
+    Ok(target)
+}
+
+/// The dynamic linking information of an executable or shared library. Strings are as they
+/// appear in the file, without their NUL terminators.
+#[derive(Debug)]
+pub(crate) struct DynamicInfo {
+    /// The class and machine the code is for
+    pub target: Target,
+    /// The program interpreter from `PT_INTERP`
+    pub interp: Option<Vec<u8>>,
+    /// The `DT_NEEDED` entries, in order
+    pub needed: Vec<Vec<u8>>,
+    /// The `DT_SONAME` entry of a shared library
+    pub soname: Option<Vec<u8>>,
+    /// The `DT_RPATH` entry
+    pub rpath: Option<Vec<u8>>,
+    /// The `DT_RUNPATH` entry
+    pub runpath: Option<Vec<u8>>,
+}
+
+/// Read the dynamic linking information of the `len` bytes of code read by `read_at`, after
+/// checking them like `validate` does
+pub(crate) fn dynamic_info<R>(len: u64, mut read_at: R) -> Result<DynamicInfo>
+where
+    R: FnMut(u64, &mut [u8]) -> Result<()>,
+{
+    let target = validate(len, &mut read_at)?;
+
+    let layout = layout(target.class);
+    let mut ehdr = [0; LAYOUT_64.ehdr_size as usize];
+    let ehdr = &mut ehdr[..layout.ehdr_size as usize];
+    read_at(0, ehdr)?;
+    let phoff = read_uint(ehdr, layout.phoff);
+    let phnum = read_u16(ehdr, layout.phnum) as u64;
+
+    // Loadable segments as (address, offset, size), for finding the string table
+    let mut loads = Vec::new();
+    let mut interp = None;
+    let mut dynamic = None;
+    let mut phdr = [0; LAYOUT_64.phdr_size as usize];
+    let phdr = &mut phdr[..layout.phdr_size as usize];
+    for index in 0..phnum {
+        read_at(phoff + index * layout.phdr_size, phdr)?;
+        let p_type = u32::from_ne_bytes(phdr[..size_of::<u32>()].try_into().unwrap());
+        let offset = read_uint(phdr, layout.p_offset);
+        let size = read_uint(phdr, layout.p_filesz);
+        match p_type {
+            PT_LOAD => loads.push((read_uint(phdr, layout.p_vaddr), offset, size)),
+            PT_INTERP => interp = Some((offset, size)),
+            PT_DYNAMIC => dynamic = Some((offset, size)),
+            _ => {}
+        }
+    }
+
+    // `validate` doesn't check segments without file contents, but these must have some
+    let in_bounds = |&(offset, size): &(u64, u64)| {
+        size != 0 && offset.checked_add(size).is_some_and(|end| end <= len)
+    };
+    if !interp.iter().chain(&dynamic).all(in_bounds) {
+        return Err(InvalidExecutable::BadDynamicSegment.into());
+    }
+
+    let mut info = DynamicInfo {
+        target,
+        interp: None,
+        needed: Vec::new(),
+        soname: None,
+        rpath: None,
+        runpath: None,
+    };
+    if let Some((offset, size)) = interp {
+        let mut path = vec![0; size as usize];
+        read_at(offset, &mut path)?;
+        info.interp = Some(until_nul(&path).ok_or(InvalidExecutable::BadDynamicSegment)?);
+    }
+    let Some((offset, size)) = dynamic else {
+        return Ok(info);
+    };
+    let mut entries = vec![0; size as usize];
+    read_at(offset, &mut entries)?;
+
+    let (mut strtab, mut strsz) = (None, None);
+    let (mut needed, mut soname, mut rpath, mut runpath) = (Vec::new(), None, None, None);
+    let half = layout.dyn_size / 2;
+    for entry in entries.chunks_exact(layout.dyn_size) {
+        let value = read_uint(entry, (half, half));
+        match read_uint(entry, (0, half)) {
+            DT_NULL => break,
+            DT_NEEDED => needed.push(value),
+            DT_STRTAB => strtab = Some(value),
+            DT_STRSZ => strsz = Some(value),
+            DT_SONAME => soname = Some(value),
+            DT_RPATH => rpath = Some(value),
+            DT_RUNPATH => runpath = Some(value),
+            _ => {}
+        }
+    }
+    if needed.is_empty() && soname.is_none() && rpath.is_none() && runpath.is_none() {
+        return Ok(info);
+    }
+
+    // The string table is given by its address, so find where it is in the file
+    let (Some(strtab), Some(strsz)) = (strtab, strsz) else {
+        return Err(InvalidExecutable::BadDynamicSegment.into());
+    };
+    let strtab_offset = loads
+        .iter()
+        .find(|&&(vaddr, _, size)| strtab >= vaddr && strtab - vaddr < size)
+        .map(|&(vaddr, offset, _)| offset + (strtab - vaddr))
+        .filter(|offset| offset.checked_add(strsz).is_some_and(|end| end <= len))
+        .ok_or(InvalidExecutable::BadDynamicSegment)?;
+    let mut strings = vec![0; strsz as usize];
+    read_at(strtab_offset, &mut strings)?;
+
+    let string = |offset: u64| -> Result<Vec<u8>> {
+        strings
+            .get(offset as usize..)
+            .and_then(until_nul)
+            .ok_or_else(|| InvalidExecutable::BadDynamicSegment.into())
+    };
+    info.needed = needed.into_iter().map(string).collect::<Result<_>>()?;
+    info.soname = soname.map(string).transpose()?;
+    info.rpath = rpath.map(string).transpose()?;
+    info.runpath = runpath.map(string).transpose()?;
+    Ok(info)
+}
+
+/// Copy a NUL-terminated string out of `buf`
+fn until_nul(buf: &[u8]) -> Option<Vec<u8>> {
+    let end = buf.iter().position(|&c| c == 0)?;
+    Some(buf[..end].to_vec())
+}
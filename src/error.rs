@@ -0,0 +1,450 @@
+//! Errors reported while setting up a child process, before it manages to exec the
+//! in-memory program. Failures in the forked child are serialized into the CLOEXEC pipe set
+//! up by `spawn`, and the parent turns them back into a `SpawnError` wrapped in an
+//! `io::Error`.
+
+use std::error::Error as StdError;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{Error, ErrorKind};
+
+use crate::elf::{ELFCLASS32, ELFCLASS64, ELFDATA2LSB, ELFDATA2MSB};
+
+/// The step of setting up the child process that failed
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+pub enum ExecStage {
+    /// Redirecting stdin, stdout or stderr with `dup2`
+    Dup2,
+    /// Changing into the requested working directory
+    Chdir,
+    /// Resetting the signal mask and dispositions
+    Signals,
+    /// Creating the memfd to hold the executable
+    MemfdCreate,
+    /// Writing the executable into the memfd
+    Write,
+    /// Executing the memfd with `fexecve`
+    Fexecve,
+    /// Running a closure registered with `MemFdExecutable::pre_exec`
+    PreExec,
+    /// Setting the supplementary groups with `setgroups`
+    SetGroups,
+    /// Setting the group id with `setgid`
+    SetGid,
+    /// Setting the user id with `setuid`
+    SetUid,
+    /// Moving into a process group with `setpgid`
+    ProcessGroup,
+    /// Starting a new session with `setsid`
+    SetSid,
+    /// Setting the parent death signal with `prctl`
+    ParentDeathSignal,
+    /// Mapping a file descriptor set with `MemFdExecutable::fd_map` with `dup2`
+    FdMap,
+    /// Closing the descriptors the program shouldn't inherit
+    CloseFds,
+    /// Executing the interpreter of a script
+    Interpreter,
+    /// Executing the dynamic loader to run the program with
+    Loader,
+    /// Sealing the memfd holding the code
+    Seal,
+}
+
+impl ExecStage {
+    fn to_raw(self) -> u32 {
+        match self {
+            ExecStage::Dup2 => 0,
+            ExecStage::Chdir => 1,
+            ExecStage::Signals => 2,
+            ExecStage::MemfdCreate => 3,
+            ExecStage::Write => 4,
+            ExecStage::Fexecve => 5,
+            ExecStage::PreExec => 6,
+            ExecStage::SetGroups => 7,
+            ExecStage::SetGid => 8,
+            ExecStage::SetUid => 9,
+            ExecStage::ProcessGroup => 10,
+            ExecStage::SetSid => 11,
+            ExecStage::ParentDeathSignal => 12,
+            ExecStage::FdMap => 13,
+            ExecStage::CloseFds => 14,
+            ExecStage::Interpreter => 15,
+            ExecStage::Loader => 16,
+            ExecStage::Seal => 17,
+        }
+    }
+
+    fn from_raw(raw: u32) -> Option<Self> {
+        Some(match raw {
+            0 => ExecStage::Dup2,
+            1 => ExecStage::Chdir,
+            2 => ExecStage::Signals,
+            3 => ExecStage::MemfdCreate,
+            4 => ExecStage::Write,
+            5 => ExecStage::Fexecve,
+            6 => ExecStage::PreExec,
+            7 => ExecStage::SetGroups,
+            8 => ExecStage::SetGid,
+            9 => ExecStage::SetUid,
+            10 => ExecStage::ProcessGroup,
+            11 => ExecStage::SetSid,
+            12 => ExecStage::ParentDeathSignal,
+            13 => ExecStage::FdMap,
+            14 => ExecStage::CloseFds,
+            15 => ExecStage::Interpreter,
+            16 => ExecStage::Loader,
+            17 => ExecStage::Seal,
+            _ => return None,
+        })
+    }
+}
+
+impl Display for ExecStage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(match self {
+            ExecStage::Dup2 => "dup2",
+            ExecStage::Chdir => "chdir",
+            ExecStage::Signals => "reset signals",
+            ExecStage::MemfdCreate => "memfd_create",
+            ExecStage::Write => "write",
+            ExecStage::Fexecve => "fexecve",
+            ExecStage::PreExec => "run pre_exec closure",
+            ExecStage::SetGroups => "setgroups",
+            ExecStage::SetGid => "setgid",
+            ExecStage::SetUid => "setuid",
+            ExecStage::ProcessGroup => "setpgid",
+            ExecStage::SetSid => "setsid",
+            ExecStage::ParentDeathSignal => "set parent death signal",
+            ExecStage::FdMap => "map file descriptor",
+            ExecStage::CloseFds => "close inherited file descriptors",
+            ExecStage::Interpreter => "execute interpreter",
+            ExecStage::Loader => "execute dynamic loader",
+            ExecStage::Seal => "seal memfd",
+        })
+    }
+}
+
+/// An error that occurred while setting up the child process, up to and including
+/// `fexecve`. This is returned from `MemFdExecutable::spawn` wrapped in an `io::Error`, and
+/// can be recovered with `io::Error::get_ref` and `downcast_ref::<SpawnError>()`.
+///
+/// # Examples
+///
+/// ```
+/// use std::fs::read;
+///
+/// use memfd_exec::{ExecStage, MemFdExecutable, SpawnError};
+///
+/// let err = MemFdExecutable::new("true", &read("/bin/true").unwrap())
+///     .cwd("/nonexistent")
+///     .spawn()
+///     .expect_err("should not be able to chdir");
+///
+/// let spawn_err = err
+///     .get_ref()
+///     .and_then(|e| e.downcast_ref::<SpawnError>())
+///     .expect("not a spawn error");
+/// assert_eq!(spawn_err.stage(), ExecStage::Chdir);
+/// ```
+#[derive(Debug)]
+pub struct SpawnError {
+    stage: ExecStage,
+    error: Error,
+}
+
+/// Size of a serialized `SpawnError` on the CLOEXEC pipe: stage, errno and footer
+pub(crate) const CLOEXEC_MSG_LEN: usize = 12;
+const CLOEXEC_MSG_FOOTER: [u8; 4] = *b"NOEX";
+
+impl SpawnError {
+    pub(crate) fn new(stage: ExecStage, error: Error) -> Self {
+        Self { stage, error }
+    }
+
+    /// The step of setting up the child process that failed
+    pub fn stage(&self) -> ExecStage {
+        self.stage
+    }
+
+    /// The OS error code the failing step returned, if there was one
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.error.raw_os_error()
+    }
+
+    /// Serialize this error to send it from the child to the parent. Errors without an
+    /// OS error code are sent as `EINVAL`.
+    pub(crate) fn to_bytes(&self) -> [u8; CLOEXEC_MSG_LEN] {
+        let errno = self.raw_os_error().unwrap_or(libc::EINVAL);
+        let mut bytes = [0; CLOEXEC_MSG_LEN];
+        bytes[0..4].copy_from_slice(&self.stage.to_raw().to_be_bytes());
+        bytes[4..8].copy_from_slice(&errno.to_be_bytes());
+        bytes[8..12].copy_from_slice(&CLOEXEC_MSG_FOOTER);
+        bytes
+    }
+
+    /// Deserialize an error sent by the child, returning `None` if the message is corrupt
+    pub(crate) fn from_bytes(bytes: &[u8; CLOEXEC_MSG_LEN]) -> Option<Self> {
+        let (stage, rest) = bytes.split_at(4);
+        let (errno, footer) = rest.split_at(4);
+        if footer != CLOEXEC_MSG_FOOTER {
+            return None;
+        }
+        let stage = ExecStage::from_raw(u32::from_be_bytes(stage.try_into().ok()?))?;
+        let errno = i32::from_be_bytes(errno.try_into().ok()?);
+        Some(Self::new(stage, Error::from_raw_os_error(errno)))
+    }
+}
+
+impl Display for SpawnError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "failed to {} while spawning process: {}",
+            self.stage, self.error
+        )
+    }
+}
+
+impl StdError for SpawnError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<SpawnError> for Error {
+    fn from(err: SpawnError) -> Error {
+        Error::new(err.error.kind(), err)
+    }
+}
+
+/// The reason code passed to `MemFdExecutable` isn't an executable the host can run. `spawn`
+/// checks this before forking, and returns it wrapped in an `io::Error` of kind
+/// `ErrorKind::InvalidData`. It can be recovered with `io::Error::get_ref` and
+/// `downcast_ref::<InvalidExecutable>()`.
+///
+/// Scripts run by an interpreter aren't checked.
+///
+/// # Examples
+///
+/// ```
+/// use std::fs::read;
+/// use std::io::ErrorKind;
+///
+/// use memfd_exec::{InvalidExecutable, MemFdExecutable};
+///
+/// let mut code = read("/bin/true").unwrap();
+/// code.truncate(1000);
+///
+/// let err = MemFdExecutable::new("true", &code)
+///     .spawn()
+///     .expect_err("truncated code should not execute");
+/// assert_eq!(err.kind(), ErrorKind::InvalidData);
+///
+/// let invalid = err
+///     .get_ref()
+///     .and_then(|e| e.downcast_ref::<InvalidExecutable>())
+///     .expect("not an invalid executable");
+/// println!("{invalid}");
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[non_exhaustive]
+pub enum InvalidExecutable {
+    /// The code doesn't start with the ELF magic number
+    NotElf,
+    /// The code ends before the ELF header or program headers do
+    Truncated {
+        /// The length of the code
+        len: u64,
+        /// The length the code would need to be
+        needed: u64,
+    },
+    /// The code is 32-bit on a 64-bit host that can't run any 32-bit programs, or the
+    /// other way around. The values are `EI_CLASS` values.
+    WrongClass {
+        /// The host's class
+        expected: u8,
+        /// The code's class
+        found: u8,
+    },
+    /// The code's byte order is not the host's. The values are `EI_DATA` values.
+    WrongEndianness {
+        /// The host's byte order
+        expected: u8,
+        /// The code's byte order
+        found: u8,
+    },
+    /// The code is an ELF file, but not an executable or a shared object, for example
+    /// an object file or a core dump
+    NotExecutable {
+        /// The code's `e_type`
+        e_type: u16,
+    },
+    /// The code is for an architecture the host can't run, natively or in compat mode. The
+    /// values are `e_machine` values.
+    WrongMachine {
+        /// The host's architecture
+        expected: u16,
+        /// The code's architecture
+        found: u16,
+    },
+    /// The program headers are missing or have the wrong size
+    BadProgramHeaders,
+    /// The dynamic segment, or the strings it refers to, can't be read. This is only
+    /// checked by `MemFdExecutable::inspect`.
+    BadDynamicSegment,
+    /// A segment's contents extend past the end of the code
+    SegmentOutOfBounds {
+        /// The index of the segment's program header
+        index: usize,
+        /// The offset of the segment's contents
+        offset: u64,
+        /// The size of the segment's contents
+        size: u64,
+        /// The length of the code
+        len: u64,
+    },
+}
+
+impl Display for InvalidExecutable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        fn class(class: u8) -> &'static str {
+            match class {
+                ELFCLASS32 => "32-bit",
+                ELFCLASS64 => "64-bit",
+                _ => "unknown class",
+            }
+        }
+
+        fn endianness(data: u8) -> &'static str {
+            match data {
+                ELFDATA2LSB => "little-endian",
+                ELFDATA2MSB => "big-endian",
+                _ => "unknown byte order",
+            }
+        }
+
+        match *self {
+            InvalidExecutable::NotElf => f.write_str("not an ELF file"),
+            InvalidExecutable::Truncated { len, needed } => write!(
+                f,
+                "ELF file is truncated: it is {len} bytes but needs at least {needed}"
+            ),
+            InvalidExecutable::WrongClass { expected, found } => write!(
+                f,
+                "ELF file is {} but the host is {}",
+                class(found),
+                class(expected)
+            ),
+            InvalidExecutable::WrongEndianness { expected, found } => write!(
+                f,
+                "ELF file is {} but the host is {}",
+                endianness(found),
+                endianness(expected)
+            ),
+            InvalidExecutable::NotExecutable { e_type } => {
+                write!(f, "ELF file is not an executable (e_type {e_type})")
+            }
+            InvalidExecutable::WrongMachine { expected, found } => write!(
+                f,
+                "ELF file is for another architecture (e_machine {found}, the host is {expected})"
+            ),
+            InvalidExecutable::BadProgramHeaders => {
+                f.write_str("ELF file has missing or malformed program headers")
+            }
+            InvalidExecutable::BadDynamicSegment => {
+                f.write_str("ELF file has a malformed dynamic segment")
+            }
+            InvalidExecutable::SegmentOutOfBounds {
+                index,
+                offset,
+                size,
+                len,
+            } => write!(
+                f,
+                "ELF segment {index} ({size} bytes at offset {offset}) extends past the end of \
+                 the {len} byte file"
+            ),
+        }
+    }
+}
+
+impl StdError for InvalidExecutable {}
+
+impl From<InvalidExecutable> for Error {
+    fn from(err: InvalidExecutable) -> Error {
+        Error::new(ErrorKind::InvalidData, err)
+    }
+}
+
+/// The kernel won't execute code from a memfd, because the `vm.memfd_noexec` sysctl is set
+/// to 2 (Linux 6.3 and later) in this process's pid namespace, or because the memfd was
+/// created without execute permission. This is returned in place of the bare `EACCES` from
+/// `memfd_create` or `fexecve`, wrapped in an `io::Error` of kind
+/// `ErrorKind::PermissionDenied`. It can be recovered with `io::Error::get_ref` and
+/// `downcast_ref::<MemfdExecForbidden>()`.
+///
+/// Scripts, and code run with an interpreter or loader, are only read from their memfd,
+/// so they still run when this would be returned for a binary.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MemfdExecForbidden {
+    memfd_noexec: Option<u8>,
+}
+
+impl MemfdExecForbidden {
+    pub(crate) fn new(memfd_noexec: Option<u8>) -> Self {
+        Self { memfd_noexec }
+    }
+
+    /// The value of `vm.memfd_noexec` when the error happened, if it could be read
+    pub fn memfd_noexec(&self) -> Option<u8> {
+        self.memfd_noexec
+    }
+}
+
+impl Display for MemfdExecForbidden {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self.memfd_noexec {
+            Some(2) => f.write_str("executing memfds is forbidden by vm.memfd_noexec = 2"),
+            Some(mode) => write!(
+                f,
+                "the memfd holding the code is not executable (vm.memfd_noexec = {mode})"
+            ),
+            None => f.write_str("the memfd holding the code is not executable"),
+        }
+    }
+}
+
+impl StdError for MemfdExecForbidden {}
+
+impl From<MemfdExecForbidden> for Error {
+    fn from(err: MemfdExecForbidden) -> Error {
+        Error::new(ErrorKind::PermissionDenied, err)
+    }
+}
+
+/// The error returned when `Child::wait_with_output_timeout` times out. It holds whatever
+/// output the child wrote before the timeout, and is returned wrapped in an `io::Error` of
+/// kind `ErrorKind::TimedOut`. It can be recovered with `io::Error::into_inner` and
+/// `downcast::<OutputTimeout>()`.
+#[derive(Debug)]
+pub struct OutputTimeout {
+    /// The data that the child process wrote to stdout before the timeout
+    pub stdout: Vec<u8>,
+    /// The data that the child process wrote to stderr before the timeout
+    pub stderr: Vec<u8>,
+}
+
+impl Display for OutputTimeout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str("timed out waiting for child process to exit")
+    }
+}
+
+impl StdError for OutputTimeout {}
+
+impl From<OutputTimeout> for Error {
+    fn from(err: OutputTimeout) -> Error {
+        Error::new(ErrorKind::TimedOut, err)
+    }
+}
@@ -7,27 +7,39 @@
 
 use std::{
     collections::BTreeMap,
+    env::vars_os,
     ffi::{CStr, CString, OsStr, OsString},
-    io::{Error, ErrorKind, Result},
+    fmt::{Debug, Formatter, Result as FmtResult},
+    io::{Error, ErrorKind, Read, Result},
+    iter::{self, empty},
     mem::MaybeUninit,
-    os::unix::prelude::{OsStrExt, OsStringExt},
-    path::Path,
-    ptr::null_mut,
+    os::{
+        raw::c_char,
+        unix::prelude::{AsFd, AsRawFd, OsStrExt, OsStringExt, RawFd},
+    },
+    path::{Path, PathBuf},
+    ptr::{null, null_mut},
+    result,
 };
 
-use libc::{pid_t, sigemptyset, signal};
-use nix::{
-    sys::memfd::{memfd_create, MemFdCreateFlag},
-    unistd::{close, fexecve, write},
-};
+use libc::{c_int, gid_t, pid_t, sigemptyset, signal, uid_t};
 
 use crate::{
     anon_pipe::anon_pipe,
     child::Child,
-    command_env::CommandEnv,
+    close_fds::close_other_fds,
+    code::Code,
+    command_env::{CommandEnv, EnvPolicy},
     cvt::{cvt, cvt_nz, cvt_r},
+    elf,
+    error::{ExecStage, MemfdExecForbidden, SpawnError, CLOEXEC_MSG_LEN},
+    file_desc::FileDesc,
+    image::{exec_forbidden, memfd_create, write_code, MemFdImage, SHEBANG},
+    inspect::{self, Inspection},
+    library_dir::LibraryDir,
     output::Output,
-    process::{ExitStatus, Process},
+    process::{ExitStatus, PidFd, Process},
+    seals::{add_seals, memfd_flags, Seals},
     stdio::{ChildPipes, Stdio, StdioPipes},
 };
 
@@ -72,35 +84,355 @@ pub struct MemFdExecutable<'a> {
     /// The contents of the ELF executable to run. This content can be included in the file
     /// using the `include_bytes!()` macro, or you can do fancy things like read it in from
     /// a socket.
-    code: &'a [u8],
+    code: Source<'a>,
     /// The name of the program, this value is the argv\[0\] argument to the binary when
     /// executed. If the program expects something specific here, that value should be
     /// used, otherwise any name will do
     program: CString,
-    /// The arguments to the program, excluding the program name
+    /// The arguments to the program, including the program name
     args: Vec<CString>,
-    /// The whole argv array, including the program name
+    /// The whole argv array, including the program name, as pointers into `args`
     argv: Argv,
     /// The environment variables to set for the program
     env: CommandEnv,
+    /// The environment the program starts from before `env` is applied
+    env_policy: EnvPolicy,
     /// The current working directory to set for the program
     cwd: Option<CString>,
+    /// The user id to switch to before executing the program
+    uid: Option<uid_t>,
+    /// The group id to switch to before executing the program
+    gid: Option<gid_t>,
+    /// The supplementary groups to set before executing the program
+    groups: Option<Box<[gid_t]>>,
+    /// The process group to move the program into, where 0 means a new group
+    pgroup: Option<pid_t>,
+    /// Whether to start the program in a new session
+    setsid: bool,
+    /// The signal to send the program when its parent dies
+    pdeathsig: Option<c_int>,
     /// The program's stdin handle
     pub stdin: Option<Stdio>,
     /// The program's stdout handle
     pub stdout: Option<Stdio>,
     /// The program's stderr handle
     pub stderr: Option<Stdio>,
+    /// Extra file descriptors to set up for the program, by their number in the program
+    fd_map: Vec<(RawFd, Stdio)>,
+    /// Whether to close every descriptor besides stdio and `fd_map` in the program
+    close_other_fds: bool,
+    /// The interpreter to run the code with, if it isn't a binary
+    interpreter: Option<Interpreter>,
+    /// Shared libraries bundled with the program, by the name the program needs them by
+    libraries: Vec<(OsString, Code<'a>)>,
+    /// The dynamic loader to run the program with instead of its own `PT_INTERP`
+    loader: Option<Loader<'a>>,
+    /// The seals to apply to memfds the code is copied into
+    seals: Seals,
+    /// Closures to run in the child just before exec
+    closures: PreExecHooks,
     /// Holdover from Command, whether there was a NUL in the arguments or not
     saw_nul: bool,
 }
 
+/// Where the executable's code comes from
 #[derive(Debug)]
-struct Argv(Vec<CString>);
+enum Source<'a> {
+    /// Raw code, copied into a fresh memfd on every spawn
+    Code(Code<'a>),
+    /// A memfd prepared ahead of time, executed directly
+    Image(MemFdImage),
+}
+
+impl Source<'_> {
+    /// Whether the code is a `#!` script, which is handed to its interpreter
+    fn is_script(&self) -> bool {
+        match self {
+            Source::Code(code) => code.starts_with(SHEBANG),
+            Source::Image(image) => image.is_script(),
+        }
+    }
+
+    /// The size of the code in bytes
+    fn len(&self) -> u64 {
+        match self {
+            Source::Code(code) => code.len() as u64,
+            Source::Image(image) => image.len() as u64,
+        }
+    }
+
+    /// Fill `buf` with the code at `offset`
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        match self {
+            Source::Code(code) => read_slice_at(code, offset, buf),
+            Source::Image(image) => image.fd().read_exact_at(buf, offset),
+        }
+    }
+}
+
+/// Fill `buf` with the bytes of `code` at `offset`, failing with `ErrorKind::UnexpectedEof`
+/// if they are out of bounds
+fn read_slice_at(code: &[u8], offset: u64, buf: &mut [u8]) -> Result<()> {
+    let bytes = usize::try_from(offset)
+        .ok()
+        .and_then(|offset| Some(offset..offset.checked_add(buf.len())?))
+        .and_then(|range| code.get(range))
+        .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "read past the end of the code"))?;
+    buf.copy_from_slice(bytes);
+    Ok(())
+}
+
+/// A dynamic loader to run a program with, set with `MemFdExecutable::loader`. This converts
+/// from anything `Code` does, for a loader run from memory like the program, and from paths,
+/// for a loader on disk.
+#[derive(Debug, Clone)]
+pub enum Loader<'a> {
+    /// The path of a loader on disk, like `/lib64/ld-linux-x86-64.so.2`
+    Path(PathBuf),
+    /// The code of a loader to run from memory
+    Code(Code<'a>),
+}
+
+impl<'a, C: Into<Code<'a>>> From<C> for Loader<'a> {
+    fn from(code: C) -> Self {
+        Loader::Code(code.into())
+    }
+}
+
+impl From<&Path> for Loader<'_> {
+    fn from(path: &Path) -> Self {
+        Loader::Path(path.to_owned())
+    }
+}
+
+impl From<PathBuf> for Loader<'_> {
+    fn from(path: PathBuf) -> Self {
+        Loader::Path(path)
+    }
+}
+
+impl From<&str> for Loader<'_> {
+    fn from(path: &str) -> Self {
+        Loader::Path(path.into())
+    }
+}
+
+/// The most bytes of a `#!` line the kernel reads, including the `#!`
+const SHEBANG_LEN: usize = 256;
+
+/// An interpreter set with `MemFdExecutable::interpreter`, or read from a script's `#!` line
+#[derive(Debug)]
+struct Interpreter {
+    /// The path of the interpreter on disk
+    path: CString,
+    /// Arguments to pass the interpreter before the path of the script
+    args: Vec<CString>,
+}
+
+/// Everything the child needs to exec the program, prepared before forking so the child
+/// doesn't have to allocate
+struct Prepared {
+    /// The program's environment, copied before forking so the child never reads `environ`
+    envp: CStringArray,
+    /// The descriptors to map into the child, see `setup_fd_map`
+    fd_map: Vec<(RawFd, FileDesc)>,
+    /// The lowest descriptor number the mapping can't overwrite
+    min_fd: RawFd,
+    /// The memfd holding the code
+    exec_fd: ExecFd,
+    /// The memfds holding the bundled libraries, all at least `min_fd`
+    libraries: Vec<FileDesc>,
+    /// The directory the loader finds the bundled libraries in, if there are any
+    library_dir: Option<LibraryDir>,
+    /// The memfd holding a loader to run from memory, at least `min_fd`
+    loader_fd: Option<FileDesc>,
+    /// The argv of the interpreter or dynamic loader to run the code with, if the code isn't
+    /// executed directly. Unless the loader is run from `loader_fd`, the first argument is
+    /// the path of the interpreter.
+    interpreter_argv: Option<CStringArray>,
+}
+
+/// The memfd handed to `fexecve`, prepared before forking
+enum ExecFd {
+    /// A fresh memfd holding a copy of `Source::Code`
+    Owned(FileDesc),
+    /// The memfd of a `Source::Image`
+    Image(MemFdImage),
+}
+
+impl ExecFd {
+    /// Make sure the memfd's number is at least `min_fd`, so mapping descriptors into the
+    /// child can't overwrite it
+    fn move_above(self, min_fd: RawFd) -> Result<ExecFd> {
+        if self.as_raw_fd() >= min_fd {
+            return Ok(self);
+        }
+        let fd = match self {
+            ExecFd::Owned(ref fd) => fd.duplicate_above(min_fd)?,
+            ExecFd::Image(ref image) => image.fd().duplicate_above(min_fd)?,
+        };
+        Ok(ExecFd::Owned(fd))
+    }
+}
+
+impl AsRawFd for ExecFd {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            ExecFd::Owned(fd) => fd.as_raw_fd(),
+            ExecFd::Image(image) => image.as_raw_fd(),
+        }
+    }
+}
+
+/// Closures to run in the child just before exec, registered with `pre_exec`
+#[derive(Default)]
+struct PreExecHooks(Vec<Box<dyn FnMut() -> Result<()> + Send + Sync>>);
+
+impl Debug for PreExecHooks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "[{} pre_exec closures]", self.0.len())
+    }
+}
+
+#[derive(Debug)]
+struct Argv(Vec<*const c_char>);
 
 unsafe impl Send for Argv {}
 unsafe impl Sync for Argv {}
 
+/// A null-terminated array of pointers to C strings, as passed to `exec`. The strings are
+/// owned by the array, so the pointers stay valid as long as it is alive.
+#[derive(Debug)]
+struct CStringArray {
+    items: Vec<CString>,
+    ptrs: Vec<*const c_char>,
+}
+
+impl CStringArray {
+    fn with_capacity(capacity: usize) -> Self {
+        let mut result = CStringArray {
+            items: Vec::with_capacity(capacity),
+            ptrs: Vec::with_capacity(capacity + 1),
+        };
+        result.ptrs.push(null());
+        result
+    }
+
+    fn push(&mut self, item: CString) {
+        let l = self.ptrs.len();
+        self.ptrs[l - 1] = item.as_ptr();
+        self.ptrs.push(null());
+        self.items.push(item);
+    }
+
+    fn as_ptr(&self) -> *const *const c_char {
+        self.ptrs.as_ptr()
+    }
+}
+
+/// Copy `code` into a new memfd and seal it with `seals`. Only a memfd that will be passed
+/// to `fexecve` should be created `exec`.
+fn fill_memfd(code: &[u8], seals: Seals, exec: bool) -> Result<FileDesc> {
+    let fd = memfd_create(memfd_flags(seals), exec).map_err(|e| {
+        // Executing memfds being forbidden is reported as it is, not as a failed stage
+        if e.get_ref().is_some_and(|e| e.is::<MemfdExecForbidden>()) {
+            e
+        } else {
+            SpawnError::new(ExecStage::MemfdCreate, e).into()
+        }
+    })?;
+    write_code(&fd, code).map_err(|e| SpawnError::new(ExecStage::Write, e))?;
+    add_seals(&fd, seals).map_err(|e| SpawnError::new(ExecStage::Seal, e))?;
+    Ok(fd)
+}
+
+/// Copy `code` into a new memfd numbered at least `min_fd`, sealed with `seals`, and
+/// executable if `exec`
+fn copy_to_memfd(code: &[u8], min_fd: RawFd, seals: Seals, exec: bool) -> Result<FileDesc> {
+    let fd = fill_memfd(code, seals, exec)?;
+    if fd.as_raw_fd() < min_fd {
+        fd.duplicate_above(min_fd)
+    } else {
+        Ok(fd)
+    }
+}
+
+/// Copy a bundled library into a memfd numbered at least `min_fd`, after checking that it
+/// is the library the program needs by `name`
+fn prepare_library(name: &OsStr, code: &[u8], min_fd: RawFd, seals: Seals) -> Result<FileDesc> {
+    // The name is a file name in the library directory
+    if name.is_empty() || name == "." || name == ".." || name.as_bytes().contains(&b'/') {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "invalid argument: library name {:?} is not a file name",
+                name
+            ),
+        ));
+    }
+    let info = elf::dynamic_info(code.len() as u64, |offset, buf| {
+        read_slice_at(code, offset, buf)
+    })?;
+    if info.soname.as_deref() != Some(name.as_bytes()) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "invalid argument: library {:?} has soname {:?}",
+                name,
+                info.soname.as_deref().map(String::from_utf8_lossy)
+            ),
+        ));
+    }
+    // The loader only maps libraries, it never executes them
+    copy_to_memfd(code, min_fd, seals, false)
+}
+
+/// The path a descriptor can be opened by in the program
+fn proc_fd_path(fd: RawFd) -> CString {
+    CString::new(format!("/proc/self/fd/{fd}")).expect("path has no nul bytes")
+}
+
+/// Parse the `#!` line at the start of `line` the way the kernel does: the interpreter is
+/// the first word, and everything after it, with surrounding blanks trimmed, is passed to it
+/// as one argument
+fn parse_shebang(line: &[u8]) -> Result<Interpreter> {
+    let invalid = |msg| Error::new(ErrorKind::InvalidData, msg);
+    let is_blank = |b: &u8| *b == b' ' || *b == b'\t';
+    let trim = |s: &[u8]| {
+        let start = s.iter().position(|b| !is_blank(b)).unwrap_or(s.len());
+        let end = s
+            .iter()
+            .rposition(|b| !is_blank(b))
+            .map_or(start, |end| end + 1);
+        s[start..end].to_vec()
+    };
+    let truncated = line.len() == SHEBANG_LEN && !line.contains(&b'\n');
+    let line = &line[SHEBANG.len()..];
+    let end = line
+        .iter()
+        .position(|&b| b == b'\n' || b == 0)
+        .unwrap_or(line.len());
+    let line = trim(&line[..end]);
+    let (path, arg) = line.split_at(line.iter().position(is_blank).unwrap_or(line.len()));
+    if path.is_empty() {
+        return Err(invalid("invalid script: the #! line has no interpreter"));
+    }
+    // The kernel cuts off a line that doesn't fit, but not in the middle of the interpreter
+    if truncated && arg.is_empty() {
+        return Err(invalid("invalid script: the #! line is too long"));
+    }
+    let arg = trim(arg);
+    let args = if arg.is_empty() {
+        Vec::new()
+    } else {
+        vec![CString::new(arg).expect("the line ends at a nul byte")]
+    };
+    Ok(Interpreter {
+        path: CString::new(path).expect("the line ends at a nul byte"),
+        args,
+    })
+}
+
 fn os2c(s: &OsStr, saw_nul: &mut bool) -> CString {
     CString::new(s.as_bytes()).unwrap_or_else(|_e| {
         *saw_nul = true;
@@ -108,8 +440,8 @@ fn os2c(s: &OsStr, saw_nul: &mut bool) -> CString {
     })
 }
 
-fn construct_envp(env: BTreeMap<OsString, OsString>, saw_nul: &mut bool) -> Vec<CString> {
-    let mut result = Vec::with_capacity(env.len());
+fn construct_envp(env: BTreeMap<OsString, OsString>, saw_nul: &mut bool) -> CStringArray {
+    let mut result = CStringArray::with_capacity(env.len());
     for (mut k, v) in env {
         // Reserve additional space for '=' and null terminator
         k.reserve_exact(v.len() + 2);
@@ -130,7 +462,8 @@ fn construct_envp(env: BTreeMap<OsString, OsString>, saw_nul: &mut bool) -> Vec<
 impl<'a> MemFdExecutable<'a> {
     /// Create a new MemFdExecutable with the given name and code. The name is the name of the
     /// program, and is used as the argv\[0\] argument to the program. The code is the binary
-    /// code to execute (usually, the entire contents of an ELF file).
+    /// code to execute (usually, the entire contents of an ELF file). It can be borrowed, or
+    /// owned so the executable is `'static`, see `Code`.
     ///
     /// # Examples
     ///
@@ -150,27 +483,151 @@ impl<'a> MemFdExecutable<'a> {
     ///     .expect("failed to execute process");
     /// ```
     ///
-    pub fn new<S: AsRef<OsStr>>(name: S, code: &'a [u8]) -> Self {
+    pub fn new<S: AsRef<OsStr>, C: Into<Code<'a>>>(name: S, code: C) -> Self {
+        Self::with_code(name.as_ref(), Source::Code(code.into()))
+    }
+
+    /// Create a new MemFdExecutable that runs a prepared `MemFdImage`. The image's memfd is
+    /// executed directly, so nothing is copied when the program is spawned. This is the
+    /// way to go when the same program is spawned many times.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::fs::read;
+    ///
+    /// use memfd_exec::{MemFdExecutable, MemFdImage, Stdio};
+    ///
+    /// let image = MemFdImage::new(&read("/bin/echo").unwrap()).unwrap();
+    ///
+    /// let output = MemFdExecutable::from_image("echo", &image)
+    ///     .arg("hello")
+    ///     .stdout(Stdio::piped())
+    ///     .output()
+    ///     .expect("failed to run echo");
+    /// assert_eq!(output.stdout, b"hello\n");
+    /// ```
+    pub fn from_image<S: AsRef<OsStr>>(name: S, image: &MemFdImage) -> Self {
+        Self::with_code(name.as_ref(), Source::Image(image.clone()))
+    }
+
+    /// Create a new MemFdExecutable from compressed code, which is decompressed straight into
+    /// a memfd once, here, rather than into another buffer. The memfd is executed directly
+    /// like a `MemFdImage`. See `MemFdImage::from_compressed` for the supported formats,
+    /// which each need their cargo feature enabled.
+    ///
+    /// # Examples
+    ///
+    /// ```no_compile
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let code = include_bytes!("/usr/bin/qemu-x86_64.zst");
+    ///
+    /// let status = MemFdExecutable::from_compressed("qemu-x86_64", code)
+    ///     .expect("failed to decompress qemu")
+    ///     .arg("-version")
+    ///     .status()
+    ///     .expect("failed to run qemu");
+    /// ```
+    pub fn from_compressed<S: AsRef<OsStr>>(name: S, code: &[u8]) -> Result<Self> {
+        let image = MemFdImage::from_compressed(code)?;
+        Ok(Self::with_code(name.as_ref(), Source::Image(image)))
+    }
+
+    /// Create a new MemFdExecutable from code read from `reader` until it reaches end of
+    /// file, like a socket or an entry in an archive. The code is copied into a memfd
+    /// through a small buffer as it is read, here rather than when the program is spawned,
+    /// and the memfd is executed directly like a `MemFdImage`.
+    ///
+    /// This is shorthand for `MemFdImage::from_reader` followed by `from_image`. Use those
+    /// to find out how many bytes were read, or `MemFdImage::from_reader_exact` to catch
+    /// truncated input when the size of the code is known.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::net::TcpStream;
+    ///
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let stream = TcpStream::connect("127.0.0.1:8000").unwrap();
+    ///
+    /// let status = MemFdExecutable::from_reader("payload", stream)
+    ///     .expect("failed to receive payload")
+    ///     .status()
+    ///     .expect("failed to run payload");
+    /// ```
+    pub fn from_reader<S: AsRef<OsStr>, R: Read>(name: S, reader: R) -> Result<Self> {
+        let image = MemFdImage::from_reader(reader)?;
+        Ok(Self::with_code(name.as_ref(), Source::Image(image)))
+    }
+
+    /// Create a new MemFdExecutable from the file at `path`, like `from_fd`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use memfd_exec::{MemFdExecutable, Stdio};
+    ///
+    /// let output = MemFdExecutable::from_file("echo", "/bin/echo")
+    ///     .expect("failed to copy echo")
+    ///     .arg("hello")
+    ///     .stdout(Stdio::piped())
+    ///     .output()
+    ///     .expect("failed to run echo");
+    /// assert_eq!(output.stdout, b"hello\n");
+    /// ```
+    pub fn from_file<S: AsRef<OsStr>, P: AsRef<Path>>(name: S, path: P) -> Result<Self> {
+        let image = MemFdImage::from_file(path)?;
+        Ok(Self::with_code(name.as_ref(), Source::Image(image)))
+    }
+
+    /// Create a new MemFdExecutable from the file open as `fd`. The file is copied into a
+    /// sealed memfd within the kernel, here rather than when the program is spawned, and
+    /// the memfd is executed directly like a `MemFdImage`. See `MemFdImage::from_fd` for
+    /// details.
+    pub fn from_fd<S: AsRef<OsStr>, F: AsFd>(name: S, fd: F) -> Result<Self> {
+        let image = MemFdImage::from_fd(fd)?;
+        Ok(Self::with_code(name.as_ref(), Source::Image(image)))
+    }
+
+    fn with_code(name: &OsStr, code: Source<'a>) -> Self {
         let mut saw_nul = false;
-        let name = os2c(name.as_ref(), &mut saw_nul);
+        let program = os2c(name, &mut saw_nul);
         Self {
             code,
-            program: name.clone(),
-            args: vec![name.clone()],
-            argv: Argv(vec![name]),
+            argv: Argv(vec![program.as_ptr(), null()]),
+            args: vec![program.clone()],
+            program,
             env: Default::default(),
+            env_policy: Default::default(),
             cwd: None,
+            uid: None,
+            gid: None,
+            groups: None,
+            pgroup: None,
+            setsid: false,
+            pdeathsig: None,
             stdin: None,
             stdout: None,
             stderr: None,
+            fd_map: Vec::new(),
+            close_other_fds: false,
+            interpreter: None,
+            libraries: Vec::new(),
+            loader: None,
+            seals: Seals::default(),
+            closures: Default::default(),
             saw_nul,
         }
     }
 
     /// Add an argument to the program. This is equivalent to `Command::arg()`.
     pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        // Overwrite the trailing null pointer in `argv` and then add a new null pointer
         let arg = os2c(arg.as_ref(), &mut self.saw_nul);
-        self.argv.0.push(arg.clone());
+        self.argv.0[self.args.len()] = arg.as_ptr();
+        self.argv.0.push(null());
         self.args.push(arg);
         self
     }
@@ -222,12 +679,113 @@ impl<'a> MemFdExecutable<'a> {
         self
     }
 
+    /// Set the environment the program starts from, before any changes made with `env`,
+    /// `envs` and `env_remove` are applied. The default is `EnvPolicy::Inherit`, which
+    /// passes the parent's environment through just like `Command`.
+    ///
+    /// # Examples
+    ///
+    /// Only pass `PATH` through from the parent, and add a variable of our own:
+    ///
+    /// ```
+    /// use std::fs::read;
+    ///
+    /// use memfd_exec::{EnvPolicy, MemFdExecutable, Stdio};
+    ///
+    /// let output = MemFdExecutable::new("env", &read("/usr/bin/env").unwrap())
+    ///     .env_policy(EnvPolicy::Allowlist(vec!["PATH".into()]))
+    ///     .env("GREETING", "hello")
+    ///     .stdout(Stdio::piped())
+    ///     .output()
+    ///     .expect("failed to run env");
+    ///
+    /// let stdout = String::from_utf8(output.stdout).unwrap();
+    /// assert!(stdout.lines().all(|l| l.starts_with("PATH=") || l == "GREETING=hello"));
+    /// ```
+    pub fn env_policy(&mut self, policy: EnvPolicy) -> &mut Self {
+        self.env_policy = policy;
+        self
+    }
+
     /// Set the current working directory for the program. This is equivalent to `Command::current_dir()`.
     pub fn cwd<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
         self.cwd = Some(os2c(dir.as_ref().as_ref(), &mut self.saw_nul));
         self
     }
 
+    /// Set the user id the program runs as. This is equivalent to `CommandExt::uid()`. The
+    /// child calls `setuid` before executing the program, so this usually requires root.
+    /// If no supplementary groups were set with `groups`, the child also tries to drop all
+    /// of its supplementary groups first, so it doesn't keep any of the parent's.
+    pub fn uid(&mut self, id: u32) -> &mut Self {
+        self.uid = Some(id as uid_t);
+        self
+    }
+
+    /// Set the group id the program runs as. This is equivalent to `CommandExt::gid()`.
+    pub fn gid(&mut self, id: u32) -> &mut Self {
+        self.gid = Some(id as gid_t);
+        self
+    }
+
+    /// Set the supplementary groups the program runs with. This is equivalent to
+    /// `CommandExt::groups()`.
+    ///
+    /// # Examples
+    ///
+    /// Drop from root to the `nobody` user before running the program:
+    ///
+    /// ```no_run
+    /// use std::fs::read;
+    ///
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let code = read("/usr/bin/id").unwrap();
+    /// let status = MemFdExecutable::new("id", &code)
+    ///     .groups(&[65534])
+    ///     .gid(65534)
+    ///     .uid(65534)
+    ///     .status()
+    ///     .expect("failed to run id");
+    /// ```
+    pub fn groups(&mut self, groups: &[u32]) -> &mut Self {
+        self.groups = Some(groups.iter().map(|&g| g as gid_t).collect());
+        self
+    }
+
+    /// Set the process group the program runs in. This is equivalent to
+    /// `CommandExt::process_group()`. A `pgroup` of 0 puts the program in a new process
+    /// group with the same id as its pid, so `Child::kill_group` can signal it along with
+    /// everything it forks.
+    pub fn process_group(&mut self, pgroup: i32) -> &mut Self {
+        self.pgroup = Some(pgroup);
+        self
+    }
+
+    /// Start the program in a new session, detached from the controlling terminal. This is
+    /// equivalent to `CommandExt::setsid()`. The program is also the leader of a new
+    /// process group, so `Child::kill_group` can signal it along with everything it forks.
+    /// This can't be combined with `process_group`, since the leader of a process group
+    /// can't start a new session, and spawning fails with `ErrorKind::InvalidInput` if both
+    /// are set.
+    pub fn setsid(&mut self, setsid: bool) -> &mut Self {
+        self.setsid = setsid;
+        self
+    }
+
+    /// Set a signal, like `libc::SIGKILL`, to send the program when its parent dies, so it
+    /// doesn't outlive a launcher that crashes. This uses `PR_SET_PDEATHSIG`. If the parent
+    /// has already died by the time the child sets this up, the child sends itself the
+    /// signal instead.
+    ///
+    /// Note that the kernel sends the signal when the *thread* that spawned the program
+    /// exits, not when the whole parent process does, so programs should be spawned from a
+    /// thread that lives as long as they should.
+    pub fn parent_death_signal(&mut self, signal: i32) -> &mut Self {
+        self.pdeathsig = Some(signal);
+        self
+    }
+
     /// Set the stdin handle for the program. This is equivalent to `Command::stdin()`. The
     /// default is to inherit the current process's stdin. Note that this `Stdio` is not the
     /// same exactly as `process::Stdio`, but it is feature-equivalent.
@@ -315,62 +873,322 @@ impl<'a> MemFdExecutable<'a> {
         self
     }
 
+    /// Make `source` available to the program as file descriptor `child_fd`, for programs
+    /// that expect descriptors beyond stdin, stdout and stderr. The source can be an
+    /// `OwnedFd`, a `File`, a pipe to another child, or a `Stdio`, where `Stdio::inherit()`
+    /// passes on the parent's own `child_fd` and `Stdio::null()` opens `/dev/null`.
+    /// `Stdio::piped()` can't be used here. Mapping the same `child_fd` again replaces the
+    /// earlier mapping.
+    ///
+    /// The mapping is safe even when the numbers of sources and targets overlap, for
+    /// example when swapping two descriptors, and the mapped descriptors are not
+    /// close-on-exec in the program. Use `stdin`, `stdout` and `stderr` to set descriptors
+    /// 0, 1 and 2; mapping them here makes `spawn` fail with `ErrorKind::InvalidInput`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::fs::read;
+    ///
+    /// use memfd_exec::{MemFdExecutable, Stdio};
+    ///
+    /// let mut echo = MemFdExecutable::new("echo", &read("/bin/echo").unwrap())
+    ///     .arg("hello")
+    ///     .stdout(Stdio::piped())
+    ///     .spawn()
+    ///     .expect("failed to spawn echo");
+    ///
+    /// // Hand echo's output to the shell as fd 3
+    /// let output = MemFdExecutable::new("sh", &read("/bin/sh").unwrap())
+    ///     .arg("-c")
+    ///     .arg("cat <&3")
+    ///     .fd_map(3, echo.stdout.take().unwrap())
+    ///     .stdout(Stdio::piped())
+    ///     .output()
+    ///     .expect("failed to run sh");
+    /// assert_eq!(output.stdout, b"hello\n");
+    /// echo.wait().unwrap();
+    /// ```
+    pub fn fd_map<T: Into<Stdio>>(&mut self, child_fd: RawFd, source: T) -> &mut Self {
+        self.fd_map.retain(|&(fd, _)| fd != child_fd);
+        self.fd_map.push((child_fd, source.into()));
+        self
+    }
+
+    /// Run the code with the interpreter at `path`, for scripts without a `#!` line or to
+    /// override the one they have. The interpreter is passed `args`, then the path of the
+    /// script under `/proc/self/fd`, then the program's arguments, like the kernel does for
+    /// `#!` scripts.
+    ///
+    /// Scripts that start with a `#!` line are run by the interpreter named there without
+    /// setting this. The line is read the same way the kernel reads it, but the interpreter
+    /// is run directly instead of executing the script, so the memfd holding the script
+    /// never has to be executable, even when `vm.memfd_noexec` forbids that. Either way, the
+    /// memfd is left open in the program so the interpreter can read it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use memfd_exec::{MemFdExecutable, Stdio};
+    ///
+    /// let output = MemFdExecutable::new("greet", b"echo hello $1")
+    ///     .interpreter("/bin/sh", ["-e"])
+    ///     .arg("world")
+    ///     .stdout(Stdio::piped())
+    ///     .output()
+    ///     .expect("failed to run script");
+    /// assert_eq!(output.stdout, b"hello world\n");
+    /// ```
+    pub fn interpreter<P, I, S>(&mut self, path: P, args: I) -> &mut Self
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let path = os2c(path.as_ref().as_os_str(), &mut self.saw_nul);
+        let args = args
+            .into_iter()
+            .map(|arg| os2c(arg.as_ref(), &mut self.saw_nul))
+            .collect();
+        self.interpreter = Some(Interpreter { path, args });
+        self
+    }
+
+    /// Bundle the shared library `code` with the program, under the `name` the program needs
+    /// it by, like `libfoo.so.1`. This is for dynamically linked programs whose libraries
+    /// aren't installed on the host. Setting a library with the same name again replaces
+    /// it.
+    ///
+    /// Each library is copied into its own memfd, and instead of executing the program
+    /// directly, its dynamic loader (the `PT_INTERP` of the code, or the one set with
+    /// `loader`) is run with the program and a `--library-path` of a private directory that
+    /// provides the libraries. The directory is created in `env::temp_dir()` and only holds a
+    /// symlink to `/proc/self/fd` for each library, so the libraries themselves are never
+    /// written to disk. It is searched before the program's `LD_LIBRARY_PATH`, so a bundled
+    /// library is only loaded if the program or one of its libraries lists it in
+    /// `DT_NEEDED`, just like one installed in a directory of its own. Spawning fails with
+    /// `ErrorKind::InvalidInput` if a library's `DT_SONAME` isn't `name`.
+    ///
+    /// The loader may read the directory at any time until the program exits, for example
+    /// when it calls `dlopen`, so it is only removed once the child has been waited on, or
+    /// when the `Child` is dropped. Dropping the `Child` before the program has loaded its
+    /// libraries can make it fail to start. When the program replaces the current process
+    /// with `exec`, the directory is left behind.
+    ///
+    /// This needs a loader that supports `--library-path` and `--argv0`, like glibc 2.33 or
+    /// later or musl, and can't be used to run scripts or with `interpreter`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::fs::read;
+    ///
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let code = read("greet").unwrap();
+    /// let libgreet = read("libgreet.so.1").unwrap();
+    ///
+    /// let status = MemFdExecutable::new("greet", &code)
+    ///     .library("libgreet.so.1", &libgreet)
+    ///     .status()
+    ///     .expect("failed to run greet");
+    /// ```
+    pub fn library<S, C>(&mut self, name: S, code: C) -> &mut Self
+    where
+        S: AsRef<OsStr>,
+        C: Into<Code<'a>>,
+    {
+        let name = name.as_ref();
+        self.libraries.retain(|(n, _)| n != name);
+        self.libraries.push((name.to_owned(), code.into()));
+        self
+    }
+
+    /// Run the program with the given dynamic loader instead of the one named by its
+    /// `PT_INTERP`, for programs whose loader doesn't exist on the host, like glibc programs
+    /// on a musl system. The loader can be the path of one on disk or its code, in which
+    /// case it is run from memory too. It is passed the program under `/proc/self/fd` and
+    /// the program's arguments, and the program's environment.
+    ///
+    /// This needs a loader that supports `--argv0`, like glibc 2.33 or later or musl, and
+    /// can't be used to run scripts or with `interpreter`. Libraries bundled with `library`
+    /// are found by this loader.
+    ///
+    /// # Examples
+    ///
+    /// Run a program with a loader from memory:
+    ///
+    /// ```no_run
+    /// use std::fs::read;
+    ///
+    /// use memfd_exec::{MemFdExecutable, Stdio};
+    ///
+    /// let code = read("/bin/echo").unwrap();
+    /// let loader = read("/lib64/ld-linux-x86-64.so.2").unwrap();
+    ///
+    /// let output = MemFdExecutable::new("echo", &code)
+    ///     .loader(&loader)
+    ///     .arg("hello")
+    ///     .stdout(Stdio::piped())
+    ///     .output()
+    ///     .expect("failed to run echo");
+    /// assert_eq!(output.stdout, b"hello\n");
+    /// ```
+    pub fn loader<L: Into<Loader<'a>>>(&mut self, loader: L) -> &mut Self {
+        self.loader = Some(loader.into());
+        self
+    }
+
+    /// Set the seals applied to the memfd the code is copied into when the program is
+    /// spawned, once the code has been written to it. The default, `Seals::default()`,
+    /// makes the code immutable, so nothing else holding the memfd, like the program itself
+    /// through `/proc/self/fd`, can change it while it runs. `Seals::empty()` adds no
+    /// seals. If the kernel refuses any of the seals, spawning fails with a `SpawnError` at
+    /// `ExecStage::Seal`.
+    ///
+    /// The seals apply to bundled libraries and a loader run from memory too. Images are
+    /// sealed when they are created, so this has no effect on executables created from one,
+    /// including with `from_reader`, `from_file`, `from_fd` and `from_compressed`.
+    pub fn seals(&mut self, seals: Seals) -> &mut Self {
+        self.seals = seals;
+        self
+    }
+
+    /// Close every file descriptor in the program except stdin, stdout, stderr and the ones
+    /// set with `fd_map`. Descriptors the parent opened without `O_CLOEXEC`, for example
+    /// sockets created by other libraries, are otherwise inherited by the program.
+    ///
+    /// This uses `close_range`, falling back to walking `/proc/self/fd` on kernels older
+    /// than 5.11, and happens after stdio and `fd_map` are set up, before any `pre_exec`
+    /// closures run.
+    pub fn close_other_fds(&mut self, close: bool) -> &mut Self {
+        self.close_other_fds = close;
+        self
+    }
+
+    /// Schedule a closure to be run in the child process just before the program is
+    /// executed. This is equivalent to `CommandExt::pre_exec()`. The closure runs after
+    /// stdio has been redirected, the working directory changed and signal handling reset.
+    /// If it returns an error, spawning fails with a `SpawnError` at `ExecStage::PreExec`
+    /// carrying the error's OS error code (or `EINVAL` if it has none). Multiple closures
+    /// run in the order they were registered.
+    ///
+    /// # Safety
+    ///
+    /// The closure runs in the child between `fork` and `exec`, where only
+    /// async-signal-safe operations are allowed. It must not allocate, take locks, or touch
+    /// state shared with other threads of the parent. See `CommandExt::pre_exec` for
+    /// details.
+    ///
+    /// # Examples
+    ///
+    /// Limit the size of the program's address space before it starts:
+    ///
+    /// ```
+    /// use std::fs::read;
+    /// use std::io::Error;
+    ///
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let code = read("/bin/true").unwrap();
+    /// let mut cmd = MemFdExecutable::new("true", &code);
+    /// unsafe {
+    ///     cmd.pre_exec(|| {
+    ///         let limit = libc::rlimit {
+    ///             rlim_cur: 1 << 30,
+    ///             rlim_max: 1 << 30,
+    ///         };
+    ///         if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+    ///             return Err(Error::last_os_error());
+    ///         }
+    ///         Ok(())
+    ///     });
+    /// }
+    /// assert!(cmd.status().unwrap().code() == Some(0));
+    /// ```
+    pub unsafe fn pre_exec<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnMut() -> Result<()> + Send + Sync + 'static,
+    {
+        self.closures.0.push(Box::new(f));
+        self
+    }
+
     /// Spawn the program as a child process. This is equivalent to `Command::spawn()`.
     pub fn spawn(&mut self) -> Result<Child> {
         let default = Stdio::Inherit;
         let needs_stdin = true;
-        const CLOEXEC_MSG_FOOTER: [u8; 4] = *b"NOEX";
 
-        let envp = self.capture_env();
-
-        if self.saw_nul() {
-            // TODO: Need err?
-        }
+        let mut prepared = self.prepare()?;
 
         let (ours, theirs) = self.setup_io(default, needs_stdin)?;
 
-        let (input, output) = anon_pipe()?;
-
-        // Whatever happens after the fork is almost for sure going to touch or
-        // look at the environment in one way or another (PATH in `execvp` or
-        // accessing the `environ` pointer ourselves). Make sure no other thread
-        // is accessing the environment when we do the fork itself.
-        //
-        // Note that as soon as we're done with the fork there's no need to hold
-        // a lock any more because the parent won't do anything and the child is
-        // in its own process. Thus the parent drops the lock guard while the child
-        // forgets it to avoid unlocking it on a new thread, which would be invalid.
-        // TODO: Yeah....I had to remove the env lock. Whoops! Don't multithread env with this
-        // you insane person
+        let (input, mut output) = anon_pipe()?;
+        if output.as_raw_fd() < prepared.min_fd {
+            output = output.duplicate_above(prepared.min_fd)?;
+        }
+
+        // Everything the child needs (argv, envp and the memfd) has been built above, because
+        // another thread may hold the allocator lock when we fork, so the child must not
+        // allocate and may only make async-signal-safe calls until it execs. The environment
+        // is a copy for the same reason: another thread may be calling `set_var`, so the
+        // child never reads `environ`.
+        let parent = unsafe { libc::getpid() };
         let pid = unsafe { self.do_fork()? };
 
         if pid == 0 {
             drop(input);
-            let Err(err) = (unsafe { self.do_exec(theirs, envp) }) else { unreachable!("..."); };
-            panic!("failed to exec: {}", err);
+            let Err(err) = (unsafe { self.do_exec(theirs, &prepared, parent) }) else {
+                unreachable!("...");
+            };
+            // We can't unwind or return from here, because that would run the parent's code
+            // in the child. Report the failure to the parent over the CLOEXEC pipe and exit.
+            let bytes = err.to_bytes();
+            let _ = output.write(&bytes);
+            unsafe { libc::_exit(1) }
         }
 
         drop(output);
 
-        // Safety: We obtained the pidfd from calling `clone3` with
-        // `CLONE_PIDFD` so it's valid an otherwise unowned.
-        let mut p = unsafe { Process::new(pid) };
-        let mut bytes = [0; 8];
+        // The child can't have been reaped yet, so its pid can't have been recycled and
+        // the pidfd is guaranteed to refer to it. If pidfds can't be used (before Linux
+        // 5.4), fall back to using the bare pid.
+        let pidfd = PidFd::open(pid).ok();
+        // Remember which process group the child was put in, if any, for `kill_group`. A
+        // new session or a pgroup of 0 makes the child the leader of a new group.
+        let pgid = match self.pgroup {
+            _ if self.setsid => Some(pid),
+            Some(0) => Some(pid),
+            Some(pgroup) => Some(pgroup),
+            None => None,
+        };
+        // The loader needs the library directory until it has loaded the libraries, and
+        // there's no telling when that is, so keep it until the child is reaped.
+        // Safety: The pidfd, if any, was just opened for this process and is unowned.
+        let mut p = unsafe { Process::new(pid, pidfd, pgid, prepared.library_dir.take()) };
+        let mut bytes = [0; CLOEXEC_MSG_LEN];
 
         // loop to handle EINTR
         loop {
             match input.read(&mut bytes) {
                 Ok(0) => return Ok(Child::new(p, ours)),
-                Ok(8) => {
-                    let (errno, footer) = bytes.split_at(4);
-                    assert_eq!(
-                        CLOEXEC_MSG_FOOTER, footer,
-                        "Validation on the CLOEXEC pipe failed: {:?}",
-                        bytes
-                    );
-                    let errno = i32::from_be_bytes(errno.try_into().unwrap());
+                Ok(CLOEXEC_MSG_LEN) => {
+                    let err = SpawnError::from_bytes(&bytes).unwrap_or_else(|| {
+                        panic!("Validation on the CLOEXEC pipe failed: {:?}", bytes)
+                    });
                     assert!(p.wait().is_ok(), "wait() should either return Ok or panic");
-                    return Err(Error::from_raw_os_error(errno));
+                    // A memfd that can't be executed fails with a bare EACCES, so explain it
+                    let exec_fd = match err.stage() {
+                        ExecStage::Fexecve => Some(prepared.exec_fd.as_raw_fd()),
+                        ExecStage::Loader => prepared.loader_fd.as_ref().map(AsRawFd::as_raw_fd),
+                        _ => None,
+                    };
+                    if err.raw_os_error() == Some(libc::EACCES) {
+                        if let Some(forbidden) = exec_fd.and_then(exec_forbidden) {
+                            return Err(forbidden.into());
+                        }
+                    }
+                    return Err(err.into());
                 }
                 Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
                 Err(e) => {
@@ -386,6 +1204,15 @@ impl<'a> MemFdExecutable<'a> {
         }
     }
 
+    /// Spawn the program as an async child process for use with tokio. The stdio pipes of
+    /// the returned child implement `AsyncRead` and `AsyncWrite`, and it can be awaited
+    /// with `wait`. This must be called from within a tokio runtime, and is only available
+    /// with the `tokio` feature enabled.
+    #[cfg(feature = "tokio")]
+    pub fn spawn_async(&mut self) -> Result<crate::tokio::Child> {
+        crate::tokio::Child::new(self.spawn()?)
+    }
+
     /// Spawn the program as a child process and wait for it to complete, obtaining the
     /// output and exit status. This is equivalent to `Command::output()`.
     pub fn output(&mut self) -> Result<Output> {
@@ -404,7 +1231,7 @@ impl<'a> MemFdExecutable<'a> {
     /// * `name` - The new name for the program. This will be used as the first argument
     pub fn set_program(&mut self, program: &OsStr) {
         let arg = os2c(program, &mut self.saw_nul);
-        self.argv.0[0] = arg.clone();
+        self.argv.0[0] = arg.as_ptr();
         self.args[0] = arg;
     }
 
@@ -438,18 +1265,58 @@ impl<'a> MemFdExecutable<'a> {
         self.saw_nul
     }
 
+    /// Get the descriptors to map into the child, each paired with the number it should
+    /// have there. Also returns the lowest descriptor number the mapping can't overwrite.
+    /// The descriptors are all at least that number, so the child can `dup2` them into
+    /// place in any order.
+    fn setup_fd_map(&self) -> Result<(Vec<(RawFd, FileDesc)>, RawFd)> {
+        let min_fd = self
+            .fd_map
+            .iter()
+            .map(|&(child_fd, _)| child_fd.saturating_add(1))
+            .fold(libc::STDERR_FILENO + 1, RawFd::max);
+        let mut fd_map = self
+            .fd_map
+            .iter()
+            .map(|&(child_fd, ref source)| {
+                if child_fd <= libc::STDERR_FILENO {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "invalid argument: use stdin, stdout and stderr to set fds 0 to 2",
+                    ));
+                }
+                Ok((child_fd, source.to_mapped_fd(child_fd, min_fd)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        fd_map.sort_unstable_by_key(|&(child_fd, _)| child_fd);
+        Ok((fd_map, min_fd))
+    }
+
     /// Get the current working directory for the child process.
     pub fn get_cwd(&self) -> &Option<CString> {
         &self.cwd
     }
 
-    unsafe fn do_fork(&mut self) -> Result<pid_t> {
+    unsafe fn do_fork(&self) -> Result<pid_t> {
         cvt(libc::fork())
     }
 
-    fn capture_env(&mut self) -> Option<Vec<CString>> {
-        let maybe_env = self.env.capture_if_changed();
-        maybe_env.map(|env| construct_envp(env, &mut self.saw_nul))
+    /// Build the program's environment. Even an inherited environment is copied, because
+    /// another thread may call `set_var` while the child is reading `environ` after the fork.
+    fn capture_env(&mut self) -> CStringArray {
+        let env = self.env_map();
+        construct_envp(env, &mut self.saw_nul)
+    }
+
+    /// The program's environment
+    fn env_map(&self) -> BTreeMap<OsString, OsString> {
+        match self.env_policy {
+            EnvPolicy::Inherit => self.env.capture(),
+            EnvPolicy::Clear => self.env.capture_from(empty()),
+            EnvPolicy::Allowlist(ref keys) => self
+                .env
+                .capture_from(vars_os().filter(|(k, _)| keys.contains(k))),
+        }
     }
 
     /// Execute the command as a new process, replacing the current process.
@@ -459,16 +1326,18 @@ impl<'a> MemFdExecutable<'a> {
     /// # Arguments
     /// * `default` - The default stdio to use if the child process does not specify.
     pub fn exec(&mut self, default: Stdio) -> Error {
-        let envp = self.capture_env();
-
-        if self.saw_nul() {
-            return Error::new(ErrorKind::InvalidInput, "nul byte found in provided data");
-        }
+        let prepared = match self.prepare() {
+            Ok(prepared) => prepared,
+            Err(e) => return e,
+        };
 
         match self.setup_io(default, true) {
             Ok((_, theirs)) => unsafe {
-                let Err(e) = self.do_exec(theirs, envp) else { unreachable!("..."); };
-                e
+                let parent = libc::getppid();
+                let Err(e) = self.do_exec(theirs, &prepared, parent) else {
+                    unreachable!("...");
+                };
+                e.into()
             },
             Err(e) => e,
         }
@@ -481,7 +1350,7 @@ impl<'a> MemFdExecutable<'a> {
 
     /// Get the program argv to use for the child process.
     pub fn get_argv(&self) -> &Vec<CString> {
-        &self.argv.0
+        &self.args
     }
 
     /// Get whether PATH has been affected by changes to the environment variables
@@ -495,23 +1364,270 @@ impl<'a> MemFdExecutable<'a> {
         self.program.to_bytes().contains(&b'/')
     }
 
+    /// Build everything the child needs to exec the program
+    fn prepare(&mut self) -> Result<Prepared> {
+        self.validate()?;
+        let envp = self.capture_env();
+        if self.saw_nul() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "nul byte found in provided data",
+            ));
+        }
+        let (fd_map, min_fd) = self.setup_fd_map()?;
+        let exec_fd = self.prepare_code()?.move_above(min_fd)?;
+        let libraries = self
+            .libraries
+            .iter()
+            .map(|(name, code)| prepare_library(name, code, min_fd, self.seals))
+            .collect::<Result<Vec<_>>>()?;
+        let library_dir = if libraries.is_empty() {
+            None
+        } else {
+            Some(LibraryDir::new(
+                self.libraries
+                    .iter()
+                    .zip(&libraries)
+                    .map(|((name, _), fd)| (name.as_os_str(), fd.as_raw_fd())),
+            )?)
+        };
+        let loader_fd = match self.loader {
+            Some(Loader::Code(ref code)) => {
+                elf::validate(code.len() as u64, |offset, buf| {
+                    read_slice_at(code, offset, buf)
+                })?;
+                Some(copy_to_memfd(code, min_fd, self.seals, true)?)
+            }
+            _ => None,
+        };
+        // A script's interpreter is run by us rather than by the kernel, so the memfd holding
+        // the script is only read and never has to be executable
+        let shebang = match self.interpreter {
+            None if self.code.is_script() => Some(self.read_shebang()?),
+            _ => None,
+        };
+        let interpreter_argv = match self.interpreter.as_ref().or(shebang.as_ref()) {
+            Some(interpreter) => {
+                let mut argv =
+                    CStringArray::with_capacity(interpreter.args.len() + self.args.len() + 1);
+                argv.push(interpreter.path.clone());
+                interpreter
+                    .args
+                    .iter()
+                    .cloned()
+                    .for_each(|arg| argv.push(arg));
+                argv.push(proc_fd_path(exec_fd.as_raw_fd()));
+                self.args[1..]
+                    .iter()
+                    .cloned()
+                    .for_each(|arg| argv.push(arg));
+                Some(argv)
+            }
+            None if library_dir.is_some() || self.loader.is_some() => {
+                Some(self.loader_argv(&exec_fd, library_dir.as_ref())?)
+            }
+            None => None,
+        };
+        Ok(Prepared {
+            envp,
+            fd_map,
+            min_fd,
+            exec_fd,
+            libraries,
+            library_dir,
+            loader_fd,
+            interpreter_argv,
+        })
+    }
+
+    /// Read the interpreter of a `#!` script from its first line
+    fn read_shebang(&self) -> Result<Interpreter> {
+        let mut line = [0; SHEBANG_LEN];
+        let len = self.code.len().min(SHEBANG_LEN as u64) as usize;
+        self.code.read_at(0, &mut line[..len])?;
+        parse_shebang(&line[..len])
+    }
+
+    /// Build the argv to run the code with its dynamic loader, searching `library_dir` for
+    /// libraries before the program's `LD_LIBRARY_PATH`
+    fn loader_argv(
+        &self,
+        exec_fd: &ExecFd,
+        library_dir: Option<&LibraryDir>,
+    ) -> Result<CStringArray> {
+        let loader = match self.loader {
+            Some(Loader::Path(ref path)) => {
+                CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+                    Error::new(ErrorKind::InvalidInput, "nul byte found in provided data")
+                })?
+            }
+            // A loader run from memory doesn't have a path, and only uses argv[0] in messages
+            Some(Loader::Code(_)) => CString::new("ld.so").unwrap(),
+            None => {
+                let info = elf::dynamic_info(self.code.len(), |offset, buf| {
+                    self.code.read_at(offset, buf)
+                })?;
+                let Some(loader) = info.interp else {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "invalid argument: libraries can't be bundled with a statically linked \
+                         program",
+                    ));
+                };
+                CString::new(loader).expect("interpreter has no nul bytes")
+            }
+        };
+
+        let mut argv = CStringArray::with_capacity(self.args.len() + 5);
+        argv.push(loader);
+        if let Some(library_dir) = library_dir {
+            // `--library-path` replaces the LD_LIBRARY_PATH the loader would have searched
+            let mut path = library_dir.path().as_os_str().to_owned();
+            if let Some(ld_library_path) = self.env_map().remove(OsStr::new("LD_LIBRARY_PATH")) {
+                path.push(":");
+                path.push(ld_library_path);
+            }
+            argv.push(CString::new("--library-path").unwrap());
+            argv.push(CString::new(path.into_vec()).map_err(|_| {
+                Error::new(ErrorKind::InvalidInput, "nul byte found in provided data")
+            })?);
+        }
+        argv.push(CString::new("--argv0").unwrap());
+        argv.push(self.args[0].clone());
+        argv.push(proc_fd_path(exec_fd.as_raw_fd()));
+        self.args[1..]
+            .iter()
+            .cloned()
+            .for_each(|arg| argv.push(arg));
+        Ok(argv)
+    }
+
+    /// Check that the options can be used together, and that the code is an executable the
+    /// host can run. Scripts are left to their interpreter.
+    fn validate(&self) -> Result<()> {
+        if self.setsid && self.pgroup.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid argument: setsid can't be combined with process_group",
+            ));
+        }
+        if self.interpreter.is_some() || self.code.is_script() {
+            if !self.libraries.is_empty() || self.loader.is_some() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "invalid argument: libraries and loaders can't be used with a script",
+                ));
+            }
+            return Ok(());
+        }
+        elf::validate(self.code.len(), |offset, buf| {
+            self.code.read_at(offset, buf)
+        })
+        .map(drop)
+    }
+
+    /// Report the program interpreter and shared libraries the code needs, and where the
+    /// host's dynamic loader would find each of them, so missing libraries can be caught
+    /// before spawning. Libraries are searched for in `LD_LIBRARY_PATH` as the program would
+    /// see it, the code's `DT_RPATH` and `DT_RUNPATH`, the directories in `/etc/ld.so.conf`
+    /// and the loader's default directories. Search paths using `$ORIGIN` are skipped,
+    /// since in-memory code has no directory of its own.
+    ///
+    /// This fails with an `InvalidExecutable` error if the code isn't an ELF executable for
+    /// the host. See `Inspection` for an example.
+    pub fn inspect(&self) -> Result<Inspection> {
+        let info = elf::dynamic_info(self.code.len(), |offset, buf| {
+            self.code.read_at(offset, buf)
+        })?;
+        let ld_library_path = self.env_map().remove(OsStr::new("LD_LIBRARY_PATH"));
+        Ok(inspect::inspect(info, ld_library_path.as_deref()))
+    }
+
+    /// Get a memfd holding the code to execute. Raw code is copied into a new memfd here,
+    /// in the parent, so that the child doesn't have to allocate anything after `fork`.
+    fn prepare_code(&self) -> Result<ExecFd> {
+        match self.code {
+            Source::Code(ref code) => {
+                // An interpreter or loader reads the code instead of it being executed
+                let exec = self.interpreter.is_none()
+                    && !self.code.is_script()
+                    && self.libraries.is_empty()
+                    && self.loader.is_none();
+                Ok(ExecFd::Owned(fill_memfd(code, self.seals, exec)?))
+            }
+            Source::Image(ref image) => Ok(ExecFd::Image(image.clone())),
+        }
+    }
+
+    /// Set up the child process and exec the program. This runs between `fork` and `exec`,
+    /// so it must not allocate, and may only make async-signal-safe calls. `parent` is the
+    /// pid of the process that spawned this one.
     unsafe fn do_exec(
         &mut self,
         stdio: ChildPipes,
-        maybe_envp: Option<Vec<CString>>,
-    ) -> Result<()> {
+        prepared: &Prepared,
+        parent: pid_t,
+    ) -> result::Result<(), SpawnError> {
+        let stage = |stage| move |e| SpawnError::new(stage, e);
+
         if let Some(fd) = stdio.stdin.fd() {
-            cvt_r(|| libc::dup2(fd, libc::STDIN_FILENO))?;
+            cvt_r(|| libc::dup2(fd, libc::STDIN_FILENO)).map_err(stage(ExecStage::Dup2))?;
         }
         if let Some(fd) = stdio.stdout.fd() {
-            cvt_r(|| libc::dup2(fd, libc::STDOUT_FILENO))?;
+            cvt_r(|| libc::dup2(fd, libc::STDOUT_FILENO)).map_err(stage(ExecStage::Dup2))?;
         }
         if let Some(fd) = stdio.stderr.fd() {
-            cvt_r(|| libc::dup2(fd, libc::STDERR_FILENO))?;
+            cvt_r(|| libc::dup2(fd, libc::STDERR_FILENO)).map_err(stage(ExecStage::Dup2))?;
+        }
+
+        // None of the sources can be a target, so they can't overwrite each other. The
+        // sources are close-on-exec and the targets are not.
+        for (child_fd, fd) in &prepared.fd_map {
+            cvt_r(|| libc::dup2(fd.as_raw_fd(), *child_fd)).map_err(stage(ExecStage::FdMap))?;
+        }
+
+        // Everything we still need until exec, like the memfd and the CLOEXEC pipe, is at
+        // or above `min_fd`, so it is only marked close-on-exec
+        if self.close_other_fds {
+            let keep = prepared.fd_map.iter().map(|&(child_fd, _)| child_fd);
+            close_other_fds(keep, prepared.min_fd).map_err(stage(ExecStage::CloseFds))?;
+        }
+
+        // Drop privileges in the order that keeps them available as long as needed:
+        // supplementary groups and the group id can't be changed once the user id has been
+        if let Some(ref groups) = self.groups {
+            cvt(libc::setgroups(groups.len(), groups.as_ptr()))
+                .map_err(stage(ExecStage::SetGroups))?;
+        }
+        if let Some(gid) = self.gid {
+            cvt(libc::setgid(gid)).map_err(stage(ExecStage::SetGid))?;
+        }
+        if let Some(uid) = self.uid {
+            // When dropping privileges from root, the `setgroups` call will remove any
+            // extraneous groups. We only drop groups if we have CAP_SETGID and we weren't
+            // given an explicit set of groups. If we don't call this, then even though our
+            // uid has dropped, we may still have groups that enable us to do super-user
+            // things.
+            if self.groups.is_none() {
+                if let Err(e) = cvt(libc::setgroups(0, null())) {
+                    // Here we ignore the case of not having CAP_SETGID
+                    if e.raw_os_error() != Some(libc::EPERM) {
+                        return Err(SpawnError::new(ExecStage::SetGroups, e));
+                    }
+                }
+            }
+            cvt(libc::setuid(uid)).map_err(stage(ExecStage::SetUid))?;
         }
 
         if let Some(ref cwd) = *self.get_cwd() {
-            cvt(libc::chdir(cwd.as_ptr()))?;
+            cvt(libc::chdir(cwd.as_ptr())).map_err(stage(ExecStage::Chdir))?;
+        }
+
+        if let Some(pgroup) = self.pgroup {
+            cvt(libc::setpgid(0, pgroup)).map_err(stage(ExecStage::ProcessGroup))?;
+        }
+        if self.setsid {
+            cvt(libc::setsid()).map_err(stage(ExecStage::SetSid))?;
         }
 
         {
@@ -523,57 +1639,67 @@ impl<'a> MemFdExecutable<'a> {
             // need to clean things up now to avoid confusing the program
             // we're about to run.
             let mut set = MaybeUninit::<libc::sigset_t>::uninit();
-            cvt(sigemptyset(set.as_mut_ptr()))?;
+            cvt(sigemptyset(set.as_mut_ptr())).map_err(stage(ExecStage::Signals))?;
             cvt_nz(libc::pthread_sigmask(
                 libc::SIG_SETMASK,
                 set.as_ptr(),
                 null_mut(),
-            ))?;
+            ))
+            .map_err(stage(ExecStage::Signals))?;
 
             {
                 let ret = signal(libc::SIGPIPE, libc::SIG_DFL);
                 if ret == libc::SIG_ERR {
-                    return Err(Error::last_os_error());
+                    return Err(SpawnError::new(ExecStage::Signals, Error::last_os_error()));
                 }
             }
         }
 
-        // TODO: Env resetting isn't implemented because we're using fexecve not execvp
-
-        // Map the executable last, because it's a huge hit to memory if something else failed
-        let mfd = memfd_create(
-            CString::new("rust_exec").unwrap().as_c_str(),
-            MemFdCreateFlag::MFD_CLOEXEC,
-        )
-        .unwrap();
-
-        if let Ok(n) = write(mfd, self.code) {
-            if n != self.code.len() {
-                return Err(Error::new(
-                    ErrorKind::BrokenPipe,
-                    "Failed to write to memfd",
-                ));
+        // This is set after changing credentials, which clears it, and after resetting the
+        // signal mask, so that sending the signal ourselves below actually delivers it
+        if let Some(pdeathsig) = self.pdeathsig {
+            cvt(libc::prctl(
+                libc::PR_SET_PDEATHSIG,
+                pdeathsig as libc::c_ulong,
+            ))
+            .map_err(stage(ExecStage::ParentDeathSignal))?;
+            // If the parent died before the prctl, we were reparented and the kernel will
+            // never send the signal, so do what it would have done
+            if libc::getppid() != parent {
+                cvt(libc::raise(pdeathsig)).map_err(stage(ExecStage::ParentDeathSignal))?;
             }
-        } else {
-            return Err(Error::last_os_error());
         }
 
-        let argv = self
-            .get_argv()
-            .iter()
-            .map(|s| s.as_c_str())
-            .collect::<Vec<_>>();
+        for callback in self.closures.0.iter_mut() {
+            callback().map_err(stage(ExecStage::PreExec))?;
+        }
 
-        let maybe_envp = maybe_envp.unwrap_or_default();
+        let envp = prepared.envp.as_ptr();
+        let exec_fd = prepared.exec_fd.as_raw_fd();
 
-        let envp = maybe_envp.iter().map(|s| s.as_c_str()).collect::<Vec<_>>();
+        // An interpreter or loader opens the code and libraries by their paths under
+        // /proc/self/fd, so the memfds have to stay open across exec. This only changes our
+        // copies of the descriptors, not the parent's.
+        if prepared.interpreter_argv.is_some() {
+            for fd in iter::once(exec_fd).chain(prepared.libraries.iter().map(AsRawFd::as_raw_fd)) {
+                cvt(libc::fcntl(fd, libc::F_SETFD, 0)).map_err(stage(ExecStage::Interpreter))?;
+            }
+        }
 
-        if let Err(err) = fexecve(mfd, &argv, &envp) {
-            // If we failed to exec, we need to close the memfd
-            // so that the child process doesn't leak it
-            let _ = close(mfd);
-            return Err(Error::new(ErrorKind::BrokenPipe, err));
+        // On success these never return, and on failure the caller closes the memfd
+        if let Some(ref argv) = prepared.interpreter_argv {
+            match prepared.loader_fd {
+                Some(ref loader) => libc::fexecve(loader.as_raw_fd(), argv.as_ptr(), envp),
+                None => libc::execve(argv.items[0].as_ptr(), argv.as_ptr(), envp),
+            };
+            let stage = if self.interpreter.is_some() || self.code.is_script() {
+                ExecStage::Interpreter
+            } else {
+                ExecStage::Loader
+            };
+            return Err(SpawnError::new(stage, Error::last_os_error()));
         }
-        Err(Error::last_os_error())
+        libc::fexecve(exec_fd, self.argv.0.as_ptr(), envp);
+        Err(SpawnError::new(ExecStage::Fexecve, Error::last_os_error()))
     }
 }
@@ -10,6 +10,7 @@ use std::{
         io::{AsFd, AsRawFd, FromRawFd, IntoRawFd, OwnedFd},
         prelude::{BorrowedFd, RawFd},
     },
+    ptr,
 };
 
 use crate::cvt::cvt;
@@ -70,6 +71,55 @@ impl FileDesc {
         }
     }
 
+    /// Fill `buf` from `offset`, failing with `UnexpectedEof` if the file ends first
+    pub fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy up to `len` bytes from `offset` in this file to `out` within the kernel, with
+    /// `copy_file_range`, advancing `offset` past them. Returns 0 at end of file.
+    pub fn copy_file_range(
+        &self,
+        out: &FileDesc,
+        offset: &mut u64,
+        len: usize,
+    ) -> io::Result<usize> {
+        let mut off = *offset as off64_t;
+        let n = cvt(unsafe {
+            libc::copy_file_range(
+                self.as_raw_fd(),
+                &mut off as *mut off64_t as *mut _,
+                out.as_raw_fd(),
+                ptr::null_mut(),
+                len,
+                0,
+            )
+        })?;
+        *offset = off as u64;
+        Ok(n as usize)
+    }
+
+    /// Copy up to `len` bytes from `offset` in this file to `out` within the kernel, with
+    /// `sendfile`, advancing `offset` past them. Returns 0 at end of file.
+    pub fn sendfile(&self, out: &FileDesc, offset: &mut u64, len: usize) -> io::Result<usize> {
+        let mut off = libc::off_t::try_from(*offset)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EOVERFLOW))?;
+        let n = cvt(unsafe { libc::sendfile(out.as_raw_fd(), self.as_raw_fd(), &mut off, len) })?;
+        *offset = off as u64;
+        Ok(n as usize)
+    }
+
     pub fn write(&self, buf: &[u8]) -> io::Result<usize> {
         let ret = cvt(unsafe {
             libc::write(
@@ -137,9 +187,16 @@ impl FileDesc {
     pub fn duplicate(&self) -> io::Result<FileDesc> {
         Ok(Self(self.0.try_clone()?))
     }
+
+    /// Duplicate this descriptor to the lowest free number that is at least `min_fd`. The
+    /// new descriptor is close-on-exec.
+    pub fn duplicate_above(&self, min_fd: RawFd) -> io::Result<FileDesc> {
+        let fd = cvt(unsafe { libc::fcntl(self.as_raw_fd(), libc::F_DUPFD_CLOEXEC, min_fd) })?;
+        Ok(unsafe { Self::from_raw_fd(fd) })
+    }
 }
 
-impl<'a> Read for &'a FileDesc {
+impl Read for &FileDesc {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         (**self).read(buf)
     }
@@ -163,6 +220,12 @@ impl IntoRawFd for FileDesc {
     }
 }
 
+impl From<OwnedFd> for FileDesc {
+    fn from(fd: OwnedFd) -> FileDesc {
+        Self(fd)
+    }
+}
+
 impl FromRawFd for FileDesc {
     unsafe fn from_raw_fd(raw_fd: RawFd) -> Self {
         Self(FromRawFd::from_raw_fd(raw_fd))
@@ -0,0 +1,379 @@
+//! A prepared, sealed memfd holding an executable. Creating the memfd and copying the
+//! code into it is the expensive part of spawning a large program, so a `MemFdImage` lets
+//! that happen once in the parent and be shared by any number of `MemFdExecutable`s.
+
+use std::{
+    ffi::CStr,
+    fs::{read_to_string, File},
+    io::{Error, ErrorKind, Read, Result},
+    mem::MaybeUninit,
+    os::unix::prelude::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    compress,
+    cvt::{cvt, cvt_r},
+    error::MemfdExecForbidden,
+    file_desc::FileDesc,
+    seals::{add_seals, memfd_flags, Seals},
+};
+
+const MEMFD_NAME: &CStr = c"rust_exec";
+
+/// The start of an interpreted script
+pub(crate) const SHEBANG: &[u8] = b"#!";
+
+/// The size of the buffer code read from a reader is copied through
+const COPY_BUFFER_LEN: usize = 64 * 1024;
+
+/// The most bytes copied by a single `copy_file_range` or `sendfile`
+const COPY_CHUNK_LEN: usize = 1 << 30;
+
+/// Whether the kernel supports `MFD_EXEC` and `MFD_NOEXEC_SEAL`, until a `memfd_create`
+/// only succeeds without them
+static EXEC_FLAGS_SUPPORTED: AtomicBool = AtomicBool::new(true);
+
+/// An executable that has already been copied into a sealed memfd. Cloning an image is
+/// cheap and shares the same memfd.
+///
+/// # Examples
+///
+/// ```
+/// use std::fs::read;
+///
+/// use memfd_exec::{MemFdExecutable, MemFdImage};
+///
+/// let image = MemFdImage::new(&read("/bin/true").unwrap()).unwrap();
+///
+/// for _ in 0..4 {
+///     let status = MemFdExecutable::from_image("true", &image)
+///         .status()
+///         .expect("failed to run true");
+///     assert_eq!(status.code(), Some(0));
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MemFdImage {
+    fd: Arc<FileDesc>,
+    len: usize,
+    script: bool,
+}
+
+impl MemFdImage {
+    /// Create a memfd, copy `code` into it, and seal it against writes, growing and
+    /// shrinking so it can't be modified while it is being executed.
+    pub fn new(code: &[u8]) -> Result<Self> {
+        Self::with_contents(|fd| write_code(fd, code).map(|()| code.len() as u64))
+    }
+
+    /// Create an image of compressed code, which is decompressed straight into the memfd
+    /// without ever being held in memory whole. The format is detected from the magic bytes
+    /// at the start of `code`, and is one of:
+    ///
+    /// - zstd, with the `zstd` feature
+    /// - xz, with the `xz` feature
+    /// - gzip, with the `gzip` feature
+    ///
+    /// Code that isn't compressed in any of these formats is copied as it is. Code in a
+    /// format whose feature isn't enabled fails with `ErrorKind::Unsupported`, and code that
+    /// is corrupt or truncated fails with the decompressor's error.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::fs::read;
+    ///
+    /// use memfd_exec::{MemFdExecutable, MemFdImage};
+    ///
+    /// // Compressed with `zstd -19 qemu-x86_64`
+    /// let image = MemFdImage::from_compressed(&read("qemu-x86_64.zst").unwrap()).unwrap();
+    ///
+    /// let status = MemFdExecutable::from_image("qemu-x86_64", &image)
+    ///     .arg("-version")
+    ///     .status()
+    ///     .expect("failed to run qemu");
+    /// ```
+    pub fn from_compressed(code: &[u8]) -> Result<Self> {
+        let mut decoder = compress::decoder(code)?;
+        Self::with_contents(|fd| write_reader(fd, &mut decoder))
+    }
+
+    /// Create an image of the code read from `reader`, for example a socket or an entry in
+    /// an archive, until it reaches end of file. The code is copied into the memfd through
+    /// a small buffer as it is read, so it is never held in memory whole. The number of
+    /// bytes read is the image's `len`.
+    ///
+    /// If reading fails, the error has the same kind as the reader's error and says how
+    /// many bytes were read before it. A reader that ends early can't be told apart from
+    /// one that was finished, so when the size of the code is known ahead of time, use
+    /// `from_reader_exact` to catch truncated input here instead of when it is spawned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::fs::File;
+    ///
+    /// use memfd_exec::{MemFdExecutable, MemFdImage};
+    ///
+    /// let image = MemFdImage::from_reader(File::open("/bin/true").unwrap()).unwrap();
+    /// println!("read {} bytes", image.len());
+    ///
+    /// let status = MemFdExecutable::from_image("true", &image)
+    ///     .status()
+    ///     .expect("failed to run true");
+    /// assert_eq!(status.code(), Some(0));
+    /// ```
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self> {
+        Self::with_contents(|fd| write_reader(fd, &mut reader))
+    }
+
+    /// Create an image of exactly `len` bytes of code read from `reader`, like
+    /// `from_reader`. Nothing past `len` bytes is read. If the reader ends before then,
+    /// this fails with `ErrorKind::UnexpectedEof`, saying how many bytes were read.
+    pub fn from_reader_exact<R: Read>(reader: R, len: u64) -> Result<Self> {
+        let mut reader = reader.take(len);
+        Self::with_contents(|fd| match write_reader(fd, &mut reader)? {
+            copied if copied < len => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("code ended after {copied} of {len} bytes"),
+            )),
+            copied => Ok(copied),
+        })
+    }
+
+    /// Create an image of the file at `path`, like `from_fd`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use memfd_exec::{MemFdExecutable, MemFdImage};
+    ///
+    /// let image = MemFdImage::from_file("/bin/true").unwrap();
+    ///
+    /// let status = MemFdExecutable::from_image("true", &image)
+    ///     .status()
+    ///     .expect("failed to run true");
+    /// assert_eq!(status.code(), Some(0));
+    /// ```
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_fd(File::open(path)?)
+    }
+
+    /// Create an image of the file open as `fd`, copied within the kernel without passing
+    /// through userspace. The whole file is copied, from its start, and `fd`'s file offset
+    /// is left alone. Since the image is sealed, this makes a copy of the file that can't
+    /// change while it runs, even if the file does.
+    ///
+    /// This uses `copy_file_range`, falling back to `sendfile` where it isn't supported,
+    /// like between filesystems on Linux 5.19 and later. Descriptors that aren't regular
+    /// files, like pipes and sockets, are read from until end of file, like `from_reader`.
+    pub fn from_fd<F: AsFd>(fd: F) -> Result<Self> {
+        let file = File::from(fd.as_fd().try_clone_to_owned()?);
+        let regular = file.metadata()?.is_file();
+        let src = FileDesc::from(OwnedFd::from(file));
+        Self::with_contents(|fd| {
+            if regular {
+                write_file(fd, &src)
+            } else {
+                write_reader(fd, &mut &src)
+            }
+        })
+    }
+
+    /// Create a memfd, fill it with `fill`, which returns how many bytes it wrote, and seal
+    /// it
+    fn with_contents<F: FnOnce(&FileDesc) -> Result<u64>>(fill: F) -> Result<Self> {
+        let seals = Seals::default();
+        // Images of binaries are executed directly with `fexecve`, but a script is only read
+        // by its interpreter, so it can still be run if executing memfds is forbidden
+        let (fd, forbidden) = match memfd_create(memfd_flags(seals), true) {
+            Ok(fd) => (fd, None),
+            Err(e) if e.get_ref().is_some_and(|e| e.is::<MemfdExecForbidden>()) => {
+                (memfd_create(memfd_flags(seals), false)?, Some(e))
+            }
+            Err(e) => return Err(e),
+        };
+        let len = fill(&fd)?;
+        add_seals(&fd, seals)?;
+        let mut start = [0; SHEBANG.len()];
+        let script = len >= start.len() as u64 && {
+            fd.read_exact_at(&mut start, 0)?;
+            start == SHEBANG
+        };
+        if let Some(e) = forbidden.filter(|_| !script) {
+            return Err(e);
+        }
+        Ok(Self {
+            fd: Arc::new(fd),
+            len: len as usize,
+            script,
+        })
+    }
+
+    pub(crate) fn fd(&self) -> &FileDesc {
+        &self.fd
+    }
+
+    /// Whether the image is a `#!` script rather than a binary
+    pub(crate) fn is_script(&self) -> bool {
+        self.script
+    }
+
+    /// The size of the executable in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the image is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl AsFd for MemFdImage {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl AsRawFd for MemFdImage {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Create an anonymous memfd with the given `MFD_*` flags. A memfd that will be executed
+/// with `fexecve` needs `exec`, and if executing memfds is forbidden by `vm.memfd_noexec`,
+/// this returns a `MemfdExecForbidden` error. Any other memfd is only read or mapped, so it
+/// is created with `MFD_NOEXEC_SEAL`, which every `vm.memfd_noexec` setting allows.
+pub(crate) fn memfd_create(flags: libc::c_uint, exec: bool) -> Result<FileDesc> {
+    let create = |flags| {
+        let fd = cvt(unsafe { libc::memfd_create(MEMFD_NAME.as_ptr(), flags) })?;
+        Ok(unsafe { FileDesc::from_raw_fd(fd) })
+    };
+    if !EXEC_FLAGS_SUPPORTED.load(Ordering::Relaxed) {
+        return create(flags);
+    }
+    // Ask for an executable memfd explicitly, otherwise vm.memfd_noexec = 1 makes it
+    // non-executable, and Linux 6.3 and later warn about the missing flag
+    let exec_flags = if exec {
+        libc::MFD_EXEC
+    } else {
+        libc::MFD_NOEXEC_SEAL
+    };
+    match create(flags | exec_flags) {
+        // Kernels before 6.3 don't know the flags, but execute any memfd anyway. Other flags
+        // can be the cause of an EINVAL too, so only remember that the kernel doesn't know
+        // these ones if it takes the rest without them.
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+            let fd = create(flags)?;
+            EXEC_FLAGS_SUPPORTED.store(false, Ordering::Relaxed);
+            Ok(fd)
+        }
+        Err(e) if exec && e.raw_os_error() == Some(libc::EACCES) => {
+            Err(MemfdExecForbidden::new(memfd_noexec()).into())
+        }
+        result => result,
+    }
+}
+
+/// The value of the `vm.memfd_noexec` sysctl in this process's pid namespace, or `None` on
+/// kernels before 6.3 that don't have it
+pub(crate) fn memfd_noexec() -> Option<u8> {
+    read_to_string("/proc/sys/vm/memfd_noexec")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Explain an `EACCES` from executing the memfd `fd`. If the memfd has no execute
+/// permission, because it was created while vm.memfd_noexec was set, this returns the
+/// error to report instead.
+pub(crate) fn exec_forbidden(fd: RawFd) -> Option<MemfdExecForbidden> {
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+    cvt(unsafe { libc::fstat(fd, stat.as_mut_ptr()) }).ok()?;
+    let mode = unsafe { stat.assume_init() }.st_mode;
+    (mode & 0o111 == 0).then(|| MemfdExecForbidden::new(memfd_noexec()))
+}
+
+/// Write all of `code` into `fd`, retrying on short writes and `EINTR`
+pub(crate) fn write_code(fd: &FileDesc, mut code: &[u8]) -> Result<()> {
+    while !code.is_empty() {
+        match cvt_r(|| unsafe {
+            libc::write(
+                fd.as_raw_fd(),
+                code.as_ptr() as *const libc::c_void,
+                code.len(),
+            )
+        })? {
+            0 => {
+                return Err(Error::new(
+                    ErrorKind::WriteZero,
+                    "failed to write whole executable to memfd",
+                ))
+            }
+            n => code = &code[n as usize..],
+        }
+    }
+    Ok(())
+}
+
+/// Copy everything `reader` reads into `fd` through a bounded buffer, returning the number
+/// of bytes copied. Errors from the reader keep their kind, and say how many bytes were
+/// read before them.
+pub(crate) fn write_reader<R: Read + ?Sized>(fd: &FileDesc, reader: &mut R) -> Result<u64> {
+    let mut buf = vec![0; COPY_BUFFER_LEN];
+    let mut copied = 0;
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => {
+                write_code(fd, &buf[..n])?;
+                copied += n as u64;
+            }
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                return Err(Error::new(
+                    e.kind(),
+                    format!("failed to read code after {copied} bytes: {e}"),
+                ))
+            }
+        }
+    }
+}
+
+/// Copy the whole regular file `src` into `fd` within the kernel, returning the number of
+/// bytes copied
+pub(crate) fn write_file(fd: &FileDesc, src: &FileDesc) -> Result<u64> {
+    let mut offset = 0;
+    let mut sendfile = false;
+    loop {
+        let copied = if sendfile {
+            src.sendfile(fd, &mut offset, COPY_CHUNK_LEN)
+        } else {
+            src.copy_file_range(fd, &mut offset, COPY_CHUNK_LEN)
+        };
+        match copied {
+            Ok(0) => return Ok(offset),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            // The kernel refuses some copies with `copy_file_range` that `sendfile` makes
+            Err(e)
+                if !sendfile
+                    && matches!(
+                        e.raw_os_error(),
+                        Some(libc::EXDEV | libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP)
+                    ) =>
+            {
+                sendfile = true
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
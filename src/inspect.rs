@@ -0,0 +1,304 @@
+//! Reporting which shared libraries an in-memory program needs, and where the host's dynamic
+//! loader would find them, with `MemFdExecutable::inspect`. The search follows the rules in
+//! `ld.so(8)`, with the directories in `/etc/ld.so.conf` standing in for `ld.so.cache`.
+
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::fs::{read_dir, read_to_string, File};
+use std::io::Result;
+use std::os::unix::prelude::{FileExt, OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use crate::elf::{self, DynamicInfo, Target, ELFCLASS32};
+
+const LD_SO_CONF: &str = "/etc/ld.so.conf";
+
+/// How deeply `include` directives in `ld.so.conf` are followed
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// The directories the loader of a 64-bit program searches after everything else
+const DEFAULT_DIRS_64: &[&str] = &["/lib64", "/usr/lib64", "/lib", "/usr/lib"];
+/// The directories the loader of a 32-bit program searches after everything else
+const DEFAULT_DIRS_32: &[&str] = &["/lib", "/usr/lib"];
+
+/// The dynamic linking information of an in-memory program, returned by
+/// `MemFdExecutable::inspect`. Libraries are resolved against the host the way its dynamic
+/// loader would resolve them when the program is spawned.
+///
+/// # Examples
+///
+/// ```
+/// use std::fs::read;
+///
+/// use memfd_exec::MemFdExecutable;
+///
+/// let code = read("/bin/cat").unwrap();
+/// let inspection = MemFdExecutable::new("cat", &code).inspect().unwrap();
+/// assert!(inspection.missing().is_empty());
+///
+/// for library in &inspection.libraries {
+///     println!("{:?} => {:?}", library.name, library.path);
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Inspection {
+    /// The program interpreter (the dynamic loader) from `PT_INTERP`, or `None` for a
+    /// statically linked program
+    pub interpreter: Option<PathBuf>,
+    /// The directories in the program's `DT_RPATH`
+    pub rpath: Vec<PathBuf>,
+    /// The directories in the program's `DT_RUNPATH`
+    pub runpath: Vec<PathBuf>,
+    /// Every library the program needs, directly from its `DT_NEEDED` entries or through
+    /// other libraries, in the order the loader would load them
+    pub libraries: Vec<Library>,
+}
+
+/// A shared library needed by an in-memory program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Library {
+    /// The name the library is needed by, like `libc.so.6`
+    pub name: OsString,
+    /// Where the host's loader would find the library, or `None` if it wouldn't
+    pub path: Option<PathBuf>,
+}
+
+impl Inspection {
+    /// Whether the program is statically linked, and so needs nothing from the host
+    pub fn is_static(&self) -> bool {
+        self.interpreter.is_none() && self.libraries.is_empty()
+    }
+
+    /// The interpreter and library names that the host is missing. The program can't run
+    /// unless this is empty.
+    pub fn missing(&self) -> Vec<&OsStr> {
+        let interpreter = self
+            .interpreter
+            .as_deref()
+            .filter(|interpreter| !interpreter.exists())
+            .map(Path::as_os_str);
+        let libraries = self
+            .libraries
+            .iter()
+            .filter(|library| library.path.is_none())
+            .map(|library| library.name.as_os_str());
+        interpreter.into_iter().chain(libraries).collect()
+    }
+}
+
+/// The directories an object asks for its own dependencies to be searched in
+struct Scope {
+    rpath: Vec<PathBuf>,
+    runpath: Vec<PathBuf>,
+    /// The scope of the object that loaded this one, `None` for the program
+    parent: Option<usize>,
+}
+
+impl Scope {
+    /// `origin` is the directory `$ORIGIN` stands for, which in-memory programs don't have
+    fn new(info: &DynamicInfo, origin: Option<&Path>, parent: Option<usize>) -> Self {
+        Self {
+            rpath: search_path(info.rpath.as_deref(), origin),
+            runpath: search_path(info.runpath.as_deref(), origin),
+            parent,
+        }
+    }
+}
+
+/// Resolve the program's libraries against the host, given the `LD_LIBRARY_PATH` it will
+/// be run with
+pub(crate) fn inspect(info: DynamicInfo, ld_library_path: Option<&OsStr>) -> Inspection {
+    let ld_library_path = search_path(ld_library_path.map(OsStr::as_bytes), None);
+    let target = info.target;
+    let system = system_dirs(target);
+
+    // Scope 0 is the program's own
+    let mut scopes = vec![Scope::new(&info, None, None)];
+    let mut queue = info
+        .needed
+        .iter()
+        .map(|name| (name.clone(), 0))
+        .collect::<VecDeque<_>>();
+    let mut libraries: Vec<Library> = Vec::new();
+
+    // Libraries are loaded breadth first, and each name only once
+    while let Some((name, scope)) = queue.pop_front() {
+        let name = OsString::from_vec(name);
+        if libraries.iter().any(|library| library.name == name) {
+            continue;
+        }
+
+        let path = if name.as_bytes().contains(&b'/') {
+            Some(PathBuf::from(&name)).filter(|path| is_compatible(path, target))
+        } else {
+            let object = &scopes[scope];
+            let mut dirs = Vec::new();
+            // Unless the object has a RUNPATH, the RPATHs of it and of every object that
+            // loaded it, up to the program, are searched in turn. Objects in that chain that
+            // have a RUNPATH are skipped.
+            if object.runpath.is_empty() {
+                let mut next = Some(scope);
+                while let Some(index) = next {
+                    let loader = &scopes[index];
+                    if loader.runpath.is_empty() {
+                        dirs.extend(&loader.rpath);
+                    }
+                    next = loader.parent;
+                }
+            }
+            dirs.extend(&ld_library_path);
+            dirs.extend(&object.runpath);
+            dirs.extend(&system);
+            dirs.into_iter()
+                .map(|dir| dir.join(&name))
+                .find(|path| is_compatible(path, target))
+        };
+
+        if let Some(ref path) = path {
+            if let Ok(dependency) = file_dynamic_info(path) {
+                scopes.push(Scope::new(&dependency, path.parent(), Some(scope)));
+                let scope = scopes.len() - 1;
+                queue.extend(dependency.needed.into_iter().map(|name| (name, scope)));
+            }
+        }
+        libraries.push(Library { name, path });
+    }
+
+    Inspection {
+        interpreter: info
+            .interp
+            .map(|interp| PathBuf::from(OsString::from_vec(interp))),
+        rpath: scopes[0].rpath.clone(),
+        runpath: scopes[0].runpath.clone(),
+        libraries,
+    }
+}
+
+/// Whether `path` is a shared library that can be loaded into a program for `target`, the
+/// loader skips any that aren't, like 32-bit libraries when loading a 64-bit program
+fn is_compatible(path: &Path, target: Target) -> bool {
+    File::open(path)
+        .and_then(|file| {
+            let len = file.metadata()?.len();
+            elf::validate(len, |offset, buf| file.read_exact_at(buf, offset))
+        })
+        .is_ok_and(|library| library == target)
+}
+
+fn file_dynamic_info(path: &Path) -> Result<DynamicInfo> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    elf::dynamic_info(len, |offset, buf| file.read_exact_at(buf, offset))
+}
+
+/// Split a colon-separated search path. Entries using `$ORIGIN` are dropped if there is no
+/// `origin`, as are entries using other substitutions we don't know the value of.
+fn search_path(path: Option<&[u8]>, origin: Option<&Path>) -> Vec<PathBuf> {
+    let Some(path) = path else {
+        return Vec::new();
+    };
+    path.split(|&c| c == b':')
+        .filter(|dir| !dir.is_empty())
+        .filter_map(|dir| {
+            let mut expanded = Vec::new();
+            let mut rest = dir;
+            while let Some(start) = rest.iter().position(|&c| c == b'$') {
+                expanded.extend_from_slice(&rest[..start]);
+                rest = &rest[start..];
+                let token = [&b"$ORIGIN"[..], b"${ORIGIN}"]
+                    .into_iter()
+                    .find(|token| rest.starts_with(token))?;
+                expanded.extend_from_slice(origin?.as_os_str().as_bytes());
+                rest = &rest[token.len()..];
+            }
+            expanded.extend_from_slice(rest);
+            Some(PathBuf::from(OsString::from_vec(expanded)))
+        })
+        .collect()
+}
+
+/// The directories from `ld.so.conf`, followed by the defaults of the loader for `target`
+fn system_dirs(target: Target) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    read_ld_so_conf(Path::new(LD_SO_CONF), &mut dirs, 0);
+    let defaults = match target.class {
+        ELFCLASS32 => DEFAULT_DIRS_32,
+        _ => DEFAULT_DIRS_64,
+    };
+    dirs.extend(defaults.iter().map(PathBuf::from));
+    dirs
+}
+
+fn read_ld_so_conf(path: &Path, dirs: &mut Vec<PathBuf>, depth: usize) {
+    let Ok(conf) = read_to_string(path) else {
+        return;
+    };
+    for line in conf.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if let Some(patterns) = line.strip_prefix("include ") {
+            if depth >= MAX_INCLUDE_DEPTH {
+                continue;
+            }
+            for pattern in patterns.split_whitespace() {
+                let pattern = path.parent().unwrap_or(Path::new("/")).join(pattern);
+                for include in glob(&pattern) {
+                    read_ld_so_conf(&include, dirs, depth + 1);
+                }
+            }
+        } else if !line.is_empty() && !line.starts_with("hwcap ") {
+            dirs.push(PathBuf::from(line));
+        }
+    }
+}
+
+/// List the files matching `pattern`, where only the file name may contain wildcards, in
+/// sorted order like `glob(3)`
+fn glob(pattern: &Path) -> Vec<PathBuf> {
+    let (Some(dir), Some(name)) = (pattern.parent(), pattern.file_name()) else {
+        return Vec::new();
+    };
+    if !name.as_bytes().iter().any(|&c| c == b'*' || c == b'?') {
+        return vec![pattern.to_path_buf()];
+    }
+    let Ok(entries) = read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut matches = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| wildcard_match(name.as_bytes(), entry.file_name().as_bytes()))
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    matches.sort();
+    matches
+}
+
+/// Match `name` against a `pattern` of literal bytes, `*` and `?`. Only the most recent `*`
+/// is ever backtracked to, so this takes linear space and at worst quadratic time.
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // The position just after the last `*` seen, and the name position it was retried at
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, n));
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // Let the last `*` swallow one more byte and try again from there
+                Some((after, start)) => {
+                    p = after;
+                    n = start + 1;
+                    star = Some((after, n));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
@@ -23,8 +23,8 @@
 //!
 //! // The `MemFdExecutable` struct is at near feature-parity with `std::process::Command`,
 //! // so you can use it in the same way. The only difference is that you must provide the
-//! // executable contents as a `Vec<u8>` as well as telling it the argv[0] to use.
-//! let qemu = MemFdExecutable::new("qemu-x86_64", resp.bytes().unwrap().as_ref())
+//! // executable contents, borrowed or owned, as well as telling it the argv[0] to use.
+//! let qemu = MemFdExecutable::new("qemu-x86_64", resp.bytes().unwrap().to_vec())
 //!     // We'll just get the version here, but you can do anything you want with the
 //!     // args.
 //!     .arg("-version")
@@ -51,16 +51,33 @@
 
 mod anon_pipe;
 mod child;
+mod close_fds;
+mod code;
 mod command_env;
+mod compress;
 mod cvt;
+mod elf;
+mod error;
 mod executable;
 mod file_desc;
+mod image;
+mod inspect;
+mod library_dir;
 mod output;
 mod process;
+mod seals;
 mod stdio;
+#[cfg(feature = "tokio")]
+pub mod tokio;
 
 pub use child::{Child, ChildStderr, ChildStdin, ChildStdout};
-pub use executable::MemFdExecutable;
+pub use code::Code;
+pub use command_env::EnvPolicy;
+pub use error::{ExecStage, InvalidExecutable, MemfdExecForbidden, OutputTimeout, SpawnError};
+pub use executable::{Loader, MemFdExecutable};
+pub use image::MemFdImage;
+pub use inspect::{Inspection, Library};
 pub use output::Output;
-pub use process::ExitStatus;
+pub use process::{ExitStatus, PidFd};
+pub use seals::Seals;
 pub use stdio::Stdio;
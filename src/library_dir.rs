@@ -0,0 +1,63 @@
+//! A private directory the dynamic loader searches for bundled libraries. The libraries
+//! themselves stay in their memfds: the directory only holds a symlink for each one, named
+//! by the name the program needs it by and pointing at `/proc/self/fd`, which the loader
+//! resolves in the program once it is running.
+
+use std::{
+    env::temp_dir,
+    ffi::OsStr,
+    fs::{remove_dir_all, DirBuilder},
+    io::{ErrorKind, Result},
+    os::unix::{
+        fs::{symlink, DirBuilderExt},
+        prelude::RawFd,
+    },
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Counts the directories created by this process, to give each one a unique name
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory of links to bundled libraries, removed when this is dropped
+#[derive(Debug)]
+pub(crate) struct LibraryDir {
+    path: PathBuf,
+}
+
+impl LibraryDir {
+    /// Create a directory in the temporary directory with a link named `name` to
+    /// `/proc/self/fd/<fd>` for each of `libraries`
+    pub(crate) fn new<'a, I>(libraries: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (&'a OsStr, RawFd)>,
+    {
+        let path = loop {
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            let path = temp_dir().join(format!("memfd-exec-{}-{id}", process::id()));
+            match DirBuilder::new().mode(0o700).create(&path) {
+                Ok(()) => break path,
+                // Left behind by an earlier process with the same pid
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+        };
+        let dir = Self { path };
+        for (name, fd) in libraries {
+            symlink(format!("/proc/self/fd/{fd}"), dir.path.join(name))?;
+        }
+        Ok(dir)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for LibraryDir {
+    fn drop(&mut self) {
+        // There's nothing useful to do if the directory is already gone
+        let _ = remove_dir_all(&self.path);
+    }
+}
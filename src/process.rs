@@ -3,64 +3,278 @@
 
 use libc::c_int;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
-use std::io::{Error, Result};
+use std::io::{Error, ErrorKind, Result};
+use std::mem::zeroed;
+use std::os::unix::prelude::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+use std::ptr::null_mut;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-use libc::pid_t;
+use libc::{pid_t, siginfo_t};
 
 use crate::cvt::{cvt, cvt_r};
+use crate::file_desc::FileDesc;
+use crate::library_dir::LibraryDir;
+
+/// `waitid` id type for waiting on a pidfd, missing from `libc`
+const P_PIDFD: libc::idtype_t = 3;
+
+/// How often to check on a process when waiting with a timeout and there is no pidfd
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 pub struct Process {
     pid: pid_t,
     status: Option<ExitStatus>,
+    // On Linux 5.4+, a pidfd referring to the process. Signalling and waiting go through it
+    // when it is available, so a recycled pid can never be hit.
+    pidfd: Option<PidFd>,
+    // The process group the process was put in when it was spawned, if it was given one of
+    // its own
+    pgid: Option<pid_t>,
+    // The directory the dynamic loader finds bundled libraries in, kept until the process
+    // has been reaped
+    library_dir: Option<LibraryDir>,
 }
 
 impl Process {
-    pub unsafe fn new(pid: pid_t) -> Self {
-        // Safety: If `pidfd` is nonnegative, we assume it's valid and otherwise unowned.
-        Process { pid, status: None }
+    pub(crate) unsafe fn new(
+        pid: pid_t,
+        pidfd: Option<PidFd>,
+        pgid: Option<pid_t>,
+        library_dir: Option<LibraryDir>,
+    ) -> Self {
+        Process {
+            pid,
+            status: None,
+            pidfd,
+            pgid,
+            library_dir,
+        }
     }
 
     pub fn id(&self) -> u32 {
         self.pid as u32
     }
 
+    pub fn pidfd(&self) -> Option<&PidFd> {
+        self.pidfd.as_ref()
+    }
+
     pub fn kill(&mut self) -> Result<()> {
+        self.signal(libc::SIGKILL)
+    }
+
+    pub fn signal(&mut self, signal: c_int) -> Result<()> {
         // If we've already waited on this process then the pid can be recycled
         // and used for another process, and we probably shouldn't be killing
         // random processes, so just return an error.
         if self.status.is_some() {
             Err(Error::new(
                 std::io::ErrorKind::InvalidInput,
-                "invalid argument: can't kill an exited process",
+                "invalid argument: can't signal an exited process",
             ))
+        } else if let Some(ref pidfd) = self.pidfd {
+            pidfd.send_signal(signal)
         } else {
-            cvt(unsafe { libc::kill(self.pid, libc::SIGKILL) }).map(drop)
+            cvt(unsafe { libc::kill(self.pid, signal) }).map(drop)
+        }
+    }
+
+    /// Send a signal to every process in the process group the process was spawned into.
+    /// Like `signal`, this refuses once the process has been reaped: if every process in the
+    /// group has exited by then, the group id may already belong to an unrelated group.
+    pub fn kill_group(&mut self, signal: c_int) -> Result<()> {
+        match self.pgid {
+            _ if self.status.is_some() => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid argument: can't signal the group of an exited process",
+            )),
+            Some(pgid) => cvt(unsafe { libc::killpg(pgid, signal) }).map(drop),
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid argument: process was not spawned in its own process group",
+            )),
         }
     }
 
+    /// Send SIGTERM, give the process `grace` to exit, then send SIGKILL, and reap it
+    pub fn terminate(&mut self, grace: Duration) -> Result<ExitStatus> {
+        if let Some(status) = self.try_wait()? {
+            return Ok(status);
+        }
+        match self.signal(libc::SIGTERM) {
+            // The process exited on its own but hasn't been reaped yet
+            Err(e) if e.raw_os_error() == Some(libc::ESRCH) => return self.wait(),
+            result => result?,
+        }
+        if let Some(status) = self.wait_timeout(grace)? {
+            return Ok(status);
+        }
+        match self.signal(libc::SIGKILL) {
+            Err(e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+            result => result?,
+        }
+        self.wait()
+    }
+
     pub fn wait(&mut self) -> Result<ExitStatus> {
         if let Some(status) = self.status {
             return Ok(status);
         }
-        let mut status = 0 as c_int;
-        cvt_r(|| unsafe { libc::waitpid(self.pid, &mut status, 0) })?;
-        self.status = Some(ExitStatus::new(status));
-        Ok(ExitStatus::new(status))
+        let status = if let Some(ref pidfd) = self.pidfd {
+            let mut siginfo: siginfo_t = unsafe { zeroed() };
+            cvt_r(|| unsafe {
+                libc::waitid(
+                    P_PIDFD,
+                    pidfd.as_raw_fd() as libc::id_t,
+                    &mut siginfo,
+                    libc::WEXITED,
+                )
+            })?;
+            ExitStatus::from_waitid_siginfo(siginfo)
+        } else {
+            let mut status = 0 as c_int;
+            cvt_r(|| unsafe { libc::waitpid(self.pid, &mut status, 0) })?;
+            ExitStatus::new(status)
+        };
+        self.status = Some(status);
+        self.library_dir = None;
+        Ok(status)
+    }
+
+    /// Wait for the process to exit for at most `timeout`, returning `None` if it is still
+    /// running. With a pidfd this sleeps in `poll` until the process exits, otherwise it
+    /// falls back to checking periodically. A timeout too large to have a deadline, like
+    /// `Duration::MAX`, waits until the process exits.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<ExitStatus>> {
+        let Some(deadline) = Instant::now().checked_add(timeout) else {
+            return self.wait().map(Some);
+        };
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(Some(status));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            if let Some(ref pidfd) = self.pidfd {
+                let mut fds = [libc::pollfd {
+                    fd: pidfd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                }];
+                // Round up, so we don't wake up just before the deadline and spin
+                let millis = remaining
+                    .as_nanos()
+                    .div_ceil(1_000_000)
+                    .min(c_int::MAX as u128);
+                match cvt(unsafe { libc::poll(fds.as_mut_ptr(), 1, millis as c_int) }) {
+                    Err(e) if e.kind() != ErrorKind::Interrupted => return Err(e),
+                    _ => {}
+                }
+            } else {
+                sleep(remaining.min(WAIT_POLL_INTERVAL));
+            }
+        }
     }
 
     pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
         if let Some(status) = self.status {
             return Ok(Some(status));
         }
-        let mut status = 0 as c_int;
-        let pid = cvt(unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) })?;
-        if pid == 0 {
-            Ok(None)
+        let status = if let Some(ref pidfd) = self.pidfd {
+            let mut siginfo: siginfo_t = unsafe { zeroed() };
+            cvt(unsafe {
+                libc::waitid(
+                    P_PIDFD,
+                    pidfd.as_raw_fd() as libc::id_t,
+                    &mut siginfo,
+                    libc::WEXITED | libc::WNOHANG,
+                )
+            })?;
+            // With WNOHANG, waitid leaves si_pid zeroed if the process is still running
+            if unsafe { siginfo.si_pid() } == 0 {
+                return Ok(None);
+            }
+            ExitStatus::from_waitid_siginfo(siginfo)
         } else {
-            self.status = Some(ExitStatus::new(status));
-            Ok(Some(ExitStatus::new(status)))
+            let mut status = 0 as c_int;
+            let pid = cvt(unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) })?;
+            if pid == 0 {
+                return Ok(None);
+            }
+            ExitStatus::new(status)
+        };
+        self.status = Some(status);
+        self.library_dir = None;
+        Ok(Some(status))
+    }
+}
+
+/// A file descriptor referring to a child process, obtained with `pidfd_open`. It becomes
+/// readable when the process exits, so it can be polled alongside other descriptors.
+#[derive(Debug)]
+pub struct PidFd(FileDesc);
+
+impl PidFd {
+    /// Open a pidfd for `pid`. This is race-free for our own children as long as they
+    /// haven't been reaped yet, because until then the pid can't be recycled. pidfds are
+    /// always close-on-exec.
+    ///
+    /// `pidfd_open` arrived in Linux 5.3, but waiting on a pidfd with `waitid` only in 5.4,
+    /// so this checks that the pidfd can be waited on too, and fails if it can't.
+    pub(crate) fn open(pid: pid_t) -> Result<Self> {
+        let fd = cvt(unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) })?;
+        let pidfd = PidFd(unsafe { FileDesc::from_raw_fd(fd as RawFd) });
+        // WNOWAIT leaves the process to be reaped later, even if it has already exited
+        let mut siginfo: siginfo_t = unsafe { zeroed() };
+        match cvt_r(|| unsafe {
+            libc::waitid(
+                P_PIDFD,
+                pidfd.as_raw_fd() as libc::id_t,
+                &mut siginfo,
+                libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+            )
+        }) {
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Err(no_pidfd()),
+            _ => Ok(pidfd),
         }
     }
+
+    /// Send a signal to the process with `pidfd_send_signal`
+    pub(crate) fn send_signal(&self, signal: c_int) -> Result<()> {
+        cvt(unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                self.as_raw_fd(),
+                signal,
+                null_mut::<siginfo_t>(),
+                0,
+            )
+        })
+        .map(drop)
+    }
+}
+
+impl AsFd for PidFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// The error returned when a pidfd is requested on a kernel without pidfd support
+pub(crate) fn no_pidfd() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "pidfds are not supported by this kernel",
+    )
 }
 
 /// Describes the result of a process after it has terminated.
@@ -78,6 +292,19 @@ impl ExitStatus {
         ExitStatus(status)
     }
 
+    /// Convert the `siginfo_t` filled in by `waitid` into the equivalent wait status
+    fn from_waitid_siginfo(siginfo: siginfo_t) -> ExitStatus {
+        let status = unsafe { siginfo.si_status() };
+        match siginfo.si_code {
+            libc::CLD_EXITED => ExitStatus((status & 0xff) << 8),
+            libc::CLD_KILLED => ExitStatus(status),
+            libc::CLD_DUMPED => ExitStatus(status | 0x80),
+            libc::CLD_CONTINUED => ExitStatus(0xffff),
+            libc::CLD_STOPPED | libc::CLD_TRAPPED => ExitStatus(((status & 0xff) << 8) | 0x7f),
+            _ => unreachable!("waitid() should only return the above codes"),
+        }
+    }
+
     fn exited(&self) -> bool {
         libc::WIFEXITED(self.0)
     }
@@ -92,10 +319,10 @@ impl ExitStatus {
         #[allow(clippy::useless_conversion)]
         match c_int::try_from(self.0) {
             /* was nonzero */
-            Ok(failure) => Err(Error::new(
-                std::io::ErrorKind::Other,
-                format!("process exited with status {}", failure),
-            )),
+            Ok(failure) => Err(Error::other(format!(
+                "process exited with status {}",
+                failure
+            ))),
             /* was zero, couldn't convert */
             Err(_) => Ok(()),
         }
@@ -0,0 +1,107 @@
+//! Sealing memfds, so the code in them can't be changed while it is being executed by
+//! anyone else holding the memfd, see `memfd_create(2)` and `fcntl(2)`.
+
+use std::{
+    io::Result,
+    ops::{BitOr, BitOrAssign},
+    os::unix::prelude::AsRawFd,
+};
+
+use libc::c_int;
+
+use crate::{cvt::cvt, file_desc::FileDesc};
+
+/// A set of seals to apply to a memfd once the code has been written to it, combined with
+/// `|`. Once a seal is applied, it can't be removed.
+///
+/// The default is `WRITE | GROW | SHRINK | SEAL`, which makes the code immutable.
+///
+/// # Examples
+///
+/// ```
+/// use std::fs::read;
+///
+/// use memfd_exec::{MemFdExecutable, Seals};
+///
+/// let code = read("/bin/true").unwrap();
+/// let status = MemFdExecutable::new("true", &code)
+///     .seals(Seals::WRITE | Seals::SHRINK | Seals::GROW)
+///     .status()
+///     .expect("failed to run true");
+/// assert_eq!(status.code(), Some(0));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Seals(c_int);
+
+impl Seals {
+    /// Prevent writes to the memfd (`F_SEAL_WRITE`)
+    pub const WRITE: Seals = Seals(libc::F_SEAL_WRITE);
+    /// Prevent the memfd from growing (`F_SEAL_GROW`)
+    pub const GROW: Seals = Seals(libc::F_SEAL_GROW);
+    /// Prevent the memfd from shrinking (`F_SEAL_SHRINK`)
+    pub const SHRINK: Seals = Seals(libc::F_SEAL_SHRINK);
+    /// Prevent any more seals from being added (`F_SEAL_SEAL`)
+    pub const SEAL: Seals = Seals(libc::F_SEAL_SEAL);
+    /// Prevent new writes to the memfd, while allowing existing writable mappings
+    /// (`F_SEAL_FUTURE_WRITE`, Linux 5.1)
+    pub const FUTURE_WRITE: Seals = Seals(libc::F_SEAL_FUTURE_WRITE);
+
+    /// No seals at all, so the code stays writable. This crate adds no seals of its own,
+    /// although memfds that are only read, like bundled libraries, are still created with
+    /// `MFD_NOEXEC_SEAL`, so the kernel seals them against becoming executable.
+    pub const fn empty() -> Seals {
+        Seals(0)
+    }
+
+    /// Whether there are no seals
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether all of `other`'s seals are in this set
+    pub const fn contains(self, other: Seals) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The `F_SEAL_*` flags of the seals
+    pub const fn bits(self) -> i32 {
+        self.0
+    }
+}
+
+impl Default for Seals {
+    fn default() -> Self {
+        Seals::WRITE | Seals::GROW | Seals::SHRINK | Seals::SEAL
+    }
+}
+
+impl BitOr for Seals {
+    type Output = Seals;
+
+    fn bitor(self, rhs: Seals) -> Seals {
+        Seals(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Seals {
+    fn bitor_assign(&mut self, rhs: Seals) {
+        self.0 |= rhs.0
+    }
+}
+
+/// The `MFD_*` flags to create a memfd that will be sealed with `seals`
+pub(crate) fn memfd_flags(seals: Seals) -> libc::c_uint {
+    if seals.is_empty() {
+        libc::MFD_CLOEXEC
+    } else {
+        libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING
+    }
+}
+
+/// Apply `seals` to the memfd `fd`, created with `memfd_flags(seals)`
+pub(crate) fn add_seals(fd: &FileDesc, seals: Seals) -> Result<()> {
+    if seals.is_empty() {
+        return Ok(());
+    }
+    cvt(unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_ADD_SEALS, seals.0) }).map(drop)
+}
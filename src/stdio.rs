@@ -1,11 +1,13 @@
 use std::ffi::CStr;
 use std::fs::{File, OpenOptions};
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 use std::os::raw::c_int;
-use std::os::unix::prelude::{AsRawFd, FromRawFd};
+use std::os::unix::prelude::{AsRawFd, FromRawFd, OwnedFd};
 use std::path::Path;
 
 use crate::anon_pipe::{anon_pipe, AnonPipe};
+use crate::child::{ChildStderr, ChildStdin, ChildStdout};
+use crate::cvt::cvt;
 use crate::file_desc::FileDesc;
 
 const DEV_NULL: &str = "/dev/null\0";
@@ -86,6 +88,36 @@ impl Stdio {
         }
     }
 
+    /// Get the descriptor to map to `child_fd` in the child, duplicated to a number that is
+    /// at least `min_fd`. Mapping the duplicates into place in the child then can't
+    /// overwrite the source of another mapping, whatever the numbers involved.
+    pub fn to_mapped_fd(&self, child_fd: c_int, min_fd: c_int) -> Result<FileDesc> {
+        match *self {
+            // The child gets whatever the parent has open as `child_fd`
+            Stdio::Inherit => {
+                let fd = cvt(unsafe { libc::fcntl(child_fd, libc::F_DUPFD_CLOEXEC, min_fd) })?;
+                Ok(unsafe { FileDesc::from_raw_fd(fd) })
+            }
+
+            Stdio::Fd(ref fd) => fd.duplicate_above(min_fd),
+
+            Stdio::Null => {
+                let null = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open("/dev/null")?;
+                FileDesc::from(OwnedFd::from(null)).duplicate_above(min_fd)
+            }
+
+            // There would be nowhere to return our end of the pipe
+            Stdio::MakePipe => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid argument: only stdin, stdout and stderr can be piped, map one end of \
+                 an existing pipe instead",
+            )),
+        }
+    }
+
     /// Create a pipe for this file descriptor and use it in the child process as
     /// the given file descriptor to facilitate input or output redirection. See
     /// `MemFdExecutable::stdin` for an example.
@@ -112,6 +144,36 @@ impl From<AnonPipe> for Stdio {
     }
 }
 
+impl From<OwnedFd> for Stdio {
+    fn from(fd: OwnedFd) -> Stdio {
+        Stdio::Fd(fd.into())
+    }
+}
+
+impl From<File> for Stdio {
+    fn from(file: File) -> Stdio {
+        Stdio::Fd(OwnedFd::from(file).into())
+    }
+}
+
+impl From<ChildStdin> for Stdio {
+    fn from(stdin: ChildStdin) -> Stdio {
+        stdin.0.into()
+    }
+}
+
+impl From<ChildStdout> for Stdio {
+    fn from(stdout: ChildStdout) -> Stdio {
+        stdout.0.into()
+    }
+}
+
+impl From<ChildStderr> for Stdio {
+    fn from(stderr: ChildStderr) -> Stdio {
+        stderr.0.into()
+    }
+}
+
 impl ChildStdio {
     pub fn fd(&self) -> Option<c_int> {
         match *self {
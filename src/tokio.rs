@@ -0,0 +1,306 @@
+//! Async versions of `Child` and its stdio handles for use with tokio. This module is
+//! only available with the `tokio` feature enabled.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::fs::read;
+//!
+//! use memfd_exec::{MemFdExecutable, Stdio};
+//! use tokio::io::{AsyncReadExt, AsyncWriteExt};
+//!
+//! # tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+//! let mut cat = MemFdExecutable::new("cat", &read("/bin/cat").unwrap())
+//!     .stdin(Stdio::piped())
+//!     .stdout(Stdio::piped())
+//!     .spawn_async()
+//!     .expect("failed to spawn cat");
+//!
+//! let mut stdin = cat.stdin.take().unwrap();
+//! stdin.write_all(b"hello world").await.unwrap();
+//! drop(stdin);
+//!
+//! let mut output = Vec::new();
+//! cat.stdout.take().unwrap().read_to_end(&mut output).await.unwrap();
+//! assert_eq!(output, b"hello world");
+//! assert_eq!(cat.wait().await.unwrap().code(), Some(0));
+//! # });
+//! ```
+
+use std::{
+    fmt::{Debug, Formatter, Result as FmtResult},
+    io::{ErrorKind, Result},
+    os::unix::prelude::{AsRawFd, RawFd},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use ::tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncReadExt, AsyncWrite, Interest, ReadBuf},
+    signal::unix::{signal, SignalKind},
+    try_join,
+};
+
+use crate::{anon_pipe::AnonPipe, child::Child as SyncChild, output::Output, process::ExitStatus};
+
+/// An async child process created from a `MemFdExecutable` with `spawn_async`. The child
+/// and its stdio handles must be used from within a tokio runtime.
+pub struct Child {
+    inner: SyncChild,
+    /// The input stream to the child process
+    pub stdin: Option<ChildStdin>,
+    /// The output stream from the child process
+    pub stdout: Option<ChildStdout>,
+    /// The error stream from the child process
+    pub stderr: Option<ChildStderr>,
+}
+
+impl Child {
+    pub(crate) fn new(mut inner: SyncChild) -> Result<Self> {
+        Ok(Self {
+            stdin: inner
+                .stdin
+                .take()
+                .map(|s| AsyncPipe::new(s.0))
+                .transpose()?
+                .map(ChildStdin),
+            stdout: inner
+                .stdout
+                .take()
+                .map(|s| AsyncPipe::new(s.0))
+                .transpose()?
+                .map(ChildStdout),
+            stderr: inner
+                .stderr
+                .take()
+                .map(|s| AsyncPipe::new(s.0))
+                .transpose()?
+                .map(ChildStderr),
+            inner,
+        })
+    }
+
+    /// Kill the child process
+    pub fn kill(&mut self) -> Result<()> {
+        self.inner.kill()
+    }
+
+    /// Send a signal, like `libc::SIGTERM`, to the child process. This fails if the child
+    /// has already been waited on, since its pid may belong to another process by then.
+    pub fn signal(&mut self, signal: i32) -> Result<()> {
+        self.inner.signal(signal)
+    }
+
+    /// Send a signal to every process in the child's process group. The child must have been
+    /// spawned in a process group, and not waited on yet, see `Child::kill_group` in the
+    /// crate root.
+    pub fn kill_group(&mut self, signal: i32) -> Result<()> {
+        self.inner.kill_group(signal)
+    }
+
+    /// Return the id of the child process, probably a PID
+    pub fn id(&self) -> u32 {
+        self.inner.id()
+    }
+
+    /// Set whether the child process is killed with `SIGKILL` and reaped when this handle is
+    /// dropped, if it is still running. Reaping a killed process takes very little time,
+    /// but note that it blocks the runtime thread the handle is dropped on while it does.
+    pub fn kill_on_drop(&mut self, kill_on_drop: bool) {
+        self.inner.kill_on_drop(kill_on_drop);
+    }
+
+    /// Try and wait for the child process to exit, returning the exit status code if it has
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        self.inner.try_wait()
+    }
+
+    /// Wait for the child process to exit, returning the exit status code. The wait is
+    /// driven by a pidfd for the child, or by `SIGCHLD` on kernels without pidfd support.
+    pub async fn wait(&mut self) -> Result<ExitStatus> {
+        drop(self.stdin.take());
+
+        if let Some(status) = self.inner.try_wait()? {
+            return Ok(status);
+        }
+
+        match self.inner.pidfd() {
+            Ok(pidfd) => {
+                // A pidfd becomes readable once the process has exited
+                let pidfd = AsyncFd::with_interest(pidfd.as_raw_fd(), Interest::READABLE)?;
+                loop {
+                    let mut guard = pidfd.readable().await?;
+                    match self.inner.try_wait()? {
+                        Some(status) => return Ok(status),
+                        None => guard.clear_ready(),
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Unsupported => {
+                // Register for SIGCHLD before checking again, so an exit in between isn't
+                // missed
+                let mut sigchld = signal(SignalKind::child())?;
+                loop {
+                    if let Some(status) = self.inner.try_wait()? {
+                        return Ok(status);
+                    }
+                    sigchld.recv().await;
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Wait for the child process to exit, returning the exit status code and the output
+    /// streams. Both output streams are read concurrently.
+    pub async fn wait_with_output(mut self) -> Result<Output> {
+        drop(self.stdin.take());
+
+        async fn read_to_end<R: AsyncRead + Unpin>(pipe: Option<R>) -> Result<Vec<u8>> {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                pipe.read_to_end(&mut buf).await?;
+            }
+            Ok(buf)
+        }
+
+        let (stdout, stderr) = try_join!(
+            read_to_end(self.stdout.take()),
+            read_to_end(self.stderr.take())
+        )?;
+
+        Ok(Output {
+            status: self.wait().await?,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+impl Debug for Child {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("Child")
+            .field("stdin", &self.stdin)
+            .field("stdout", &self.stdout)
+            .field("stderr", &self.stderr)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A nonblocking pipe registered with the tokio reactor
+struct AsyncPipe(AsyncFd<AnonPipe>);
+
+impl AsyncPipe {
+    fn new(pipe: AnonPipe) -> Result<Self> {
+        pipe.set_nonblocking(true)?;
+        Ok(Self(AsyncFd::new(pipe)?))
+    }
+
+    fn poll_read(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            match guard.try_io(|pipe| pipe.get_ref().read(buf.initialize_unfilled())) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) if e.kind() == ErrorKind::Interrupted => {}
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => {}
+            }
+        }
+    }
+
+    fn poll_write(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            match guard.try_io(|pipe| pipe.get_ref().write(buf)) {
+                Ok(Err(e)) if e.kind() == ErrorKind::Interrupted => {}
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => {}
+            }
+        }
+    }
+}
+
+/// An async handle to a child process’s standard input (stdin).
+pub struct ChildStdin(AsyncPipe);
+
+impl AsRawFd for ChildStdin {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.0 .0.as_raw_fd()
+    }
+}
+
+impl AsyncWrite for ChildStdin {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        self.0.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Debug for ChildStdin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("ChildStdin").finish_non_exhaustive()
+    }
+}
+
+/// An async handle to a child process’s standard output (stdout).
+pub struct ChildStdout(AsyncPipe);
+
+impl AsRawFd for ChildStdout {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.0 .0.as_raw_fd()
+    }
+}
+
+impl AsyncRead for ChildStdout {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        self.0.poll_read(cx, buf)
+    }
+}
+
+impl Debug for ChildStdout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("ChildStdout").finish_non_exhaustive()
+    }
+}
+
+/// An async handle to a child process’s stderr.
+pub struct ChildStderr(AsyncPipe);
+
+impl AsRawFd for ChildStderr {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.0 .0.as_raw_fd()
+    }
+}
+
+impl AsyncRead for ChildStderr {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        self.0.poll_read(cx, buf)
+    }
+}
+
+impl Debug for ChildStderr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("ChildStderr").finish_non_exhaustive()
+    }
+}
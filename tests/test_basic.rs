@@ -1,19 +1,34 @@
 //! Test the `ls` command from the local system
 
 use std::{
-    fs::read,
-    io::{Read, Write},
+    collections::HashSet,
+    env::{current_exe, remove_var, set_var, var_os},
+    ffi::{OsStr, OsString},
+    fs::{read, read_to_string, File},
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write},
     net::{SocketAddr, TcpStream},
+    os::{
+        fd::{AsFd, AsRawFd},
+        unix::{fs::symlink, net::UnixStream},
+    },
     path::PathBuf,
     process::{Command, Stdio as ProcessStdio},
     str,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     thread::{sleep, spawn},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use serial_test::serial;
+use tempfile::{tempdir, tempfile};
 
-use memfd_exec::{MemFdExecutable, Stdio};
+use memfd_exec::{
+    Code, EnvPolicy, ExecStage, InvalidExecutable, MemFdExecutable, MemFdImage, MemfdExecForbidden,
+    OutputTimeout, Seals, SpawnError, Stdio,
+};
 
 const TEST_STATIC_CODE: &[u8] = include_bytes!("./test_static.c");
 const CARGO_TARGET_TMPDIR: &str = env!("CARGO_TARGET_TMPDIR");
@@ -126,6 +141,1701 @@ fn test_cat_stdin() {
     );
 }
 
+#[test]
+fn test_spawn_error_chdir() {
+    let cat_contents = read("/bin/cat").expect("Could not read /bin/cat");
+    let err = MemFdExecutable::new("cat", &cat_contents)
+        .cwd("/this/directory/does/not/exist")
+        .spawn()
+        .expect_err("Spawned cat in a missing directory");
+
+    let spawn_err = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<SpawnError>())
+        .expect("Error was not a SpawnError");
+    assert_eq!(spawn_err.stage(), ExecStage::Chdir);
+    assert_eq!(spawn_err.raw_os_error(), Some(libc::ENOENT));
+}
+
+#[test]
+fn test_spawn_error_nul() {
+    let cat_contents = read("/bin/cat").expect("Could not read /bin/cat");
+    let err = MemFdExecutable::new("cat", &cat_contents)
+        .arg("nul\0byte")
+        .spawn()
+        .expect_err("Spawned cat with a nul byte in an argument");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let err = MemFdExecutable::new("cat", &cat_contents)
+        .env("MEMFD_EXEC_TEST", "nul\0byte")
+        .spawn()
+        .expect_err("Spawned cat with a nul byte in the environment");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_spawn_error_bad_elf() {
+    // Garbage is caught before forking rather than failing in fexecve
+    let err = MemFdExecutable::new("garbage", b"\x7fELF but not really")
+        .spawn()
+        .expect_err("Spawned a garbage executable");
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<InvalidExecutable>())
+        .is_some());
+}
+
+/// Spawn `code` and return why it isn't a valid executable
+fn invalid_executable(code: &[u8]) -> InvalidExecutable {
+    let err = MemFdExecutable::new("invalid", code)
+        .spawn()
+        .expect_err("Spawned an invalid executable");
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    *err.into_inner()
+        .expect("Error had no inner error")
+        .downcast::<InvalidExecutable>()
+        .expect("Error was not an InvalidExecutable")
+}
+
+#[test]
+fn test_invalid_executable() {
+    let true_contents = read("/bin/true").expect("Could not read /bin/true");
+
+    assert_eq!(invalid_executable(b"not an elf"), InvalidExecutable::NotElf);
+    assert_eq!(
+        invalid_executable(&true_contents[..8]),
+        InvalidExecutable::Truncated { len: 8, needed: 16 }
+    );
+
+    // 32-bit code may run in compat mode on a 64-bit host, so use a class that isn't either
+    let mut wrong_class = true_contents.clone();
+    wrong_class[4] = 0;
+    assert!(matches!(
+        invalid_executable(&wrong_class),
+        InvalidExecutable::WrongClass { .. }
+    ));
+
+    let mut wrong_endianness = true_contents.clone();
+    wrong_endianness[5] = if wrong_endianness[5] == 1 { 2 } else { 1 };
+    assert!(matches!(
+        invalid_executable(&wrong_endianness),
+        InvalidExecutable::WrongEndianness { .. }
+    ));
+
+    let mut wrong_machine = true_contents.clone();
+    wrong_machine[18..20].copy_from_slice(&0xbeefu16.to_ne_bytes());
+    assert!(matches!(
+        invalid_executable(&wrong_machine),
+        InvalidExecutable::WrongMachine { found: 0xbeef, .. }
+    ));
+
+    // The program headers fit, but the segments they describe don't
+    let truncated = &true_contents[..1024];
+    assert!(matches!(
+        invalid_executable(truncated),
+        InvalidExecutable::SegmentOutOfBounds { len: 1024, .. }
+    ));
+
+    // Images are checked too
+    let image = MemFdImage::new(truncated).expect("Failed to create image");
+    let err = MemFdExecutable::from_image("truncated", &image)
+        .spawn()
+        .expect_err("Spawned a truncated image");
+    assert!(matches!(
+        err.get_ref()
+            .and_then(|e| e.downcast_ref::<InvalidExecutable>()),
+        Some(InvalidExecutable::SegmentOutOfBounds { .. })
+    ));
+}
+
+/// A static ELF32 program for `machine` that exits with status 42, if it runs as i386 code
+fn elf32_program(machine: u16) -> Vec<u8> {
+    const BASE: u32 = 0x0804_8000;
+    const HEADERS_LEN: u32 = 52 + 32;
+    // xor ebx, ebx; mov bl, 42; mov eax, 1; int 0x80
+    let code = [
+        0x31, 0xdb, 0xb3, 0x2a, 0xb8, 0x01, 0x00, 0x00, 0x00, 0xcd, 0x80,
+    ];
+    let len = HEADERS_LEN + code.len() as u32;
+
+    let mut program = b"\x7fELF\x01\x01\x01".to_vec();
+    program.resize(16, 0);
+    for half in [2, machine] {
+        program.extend_from_slice(&half.to_le_bytes());
+    }
+    for word in [1, BASE + HEADERS_LEN, 52, 0, 0] {
+        program.extend_from_slice(&word.to_le_bytes());
+    }
+    for half in [52u16, 32, 1, 0, 0, 0] {
+        program.extend_from_slice(&half.to_le_bytes());
+    }
+    // One PT_LOAD segment mapping the whole file, readable and executable
+    for word in [1, 0, BASE, BASE, len, len, 5, 0x1000] {
+        program.extend_from_slice(&word.to_le_bytes());
+    }
+    program.extend_from_slice(&code);
+    program
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_compat_executable() {
+    // i386 programs are left to the kernel, which runs them unless it was built without
+    // IA32 emulation
+    match MemFdExecutable::new("exit", elf32_program(3)).status() {
+        Ok(status) => assert_eq!(status.code(), Some(42)),
+        Err(err) => {
+            let err = err
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<SpawnError>())
+                .expect("i386 program was rejected before fexecve");
+            assert_eq!(err.stage(), ExecStage::Fexecve);
+        }
+    }
+
+    // So are x32 programs, but not 32-bit programs for other architectures
+    let inspection = MemFdExecutable::new("exit", elf32_program(62))
+        .inspect()
+        .expect("Failed to inspect x32 program");
+    assert!(inspection.is_static());
+    assert_eq!(
+        invalid_executable(&elf32_program(40)),
+        InvalidExecutable::WrongMachine {
+            expected: 62,
+            found: 40
+        }
+    );
+}
+
+#[test]
+fn test_image_reuse() {
+    let cat_contents = read("/bin/cat").expect("Could not read /bin/cat");
+    let image = MemFdImage::new(&cat_contents).expect("Failed to create image");
+    assert_eq!(image.len(), cat_contents.len());
+
+    for i in 0..8 {
+        let mut cat = MemFdExecutable::from_image("cat", &image)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Failed to run cat");
+
+        let message = format!("Hello, world {}!", i);
+        cat.stdin
+            .take()
+            .expect("Failed to open stdin")
+            .write_all(message.as_bytes())
+            .expect("Failed to write to cat stdin");
+
+        let output = cat.wait_with_output().expect("Failed to run cat");
+        assert_eq!(output.stdout, message.as_bytes());
+    }
+
+    let seals = unsafe { libc::fcntl(image.as_raw_fd(), libc::F_GET_SEALS) };
+    assert_ne!(seals & libc::F_SEAL_WRITE, 0, "Image memfd is not sealed");
+}
+
+#[test]
+#[serial]
+fn test_spawn_many_threads() {
+    // Spawning from many threads at once, while other threads hammer the allocator and the
+    // environment, used to be able to deadlock a child that allocated between fork and exec
+    let cat_contents: Arc<[u8]> = read("/bin/cat").expect("Could not read /bin/cat").into();
+    let done = Arc::new(AtomicBool::new(false));
+
+    let allocators = (0..4)
+        .map(|_| {
+            let done = done.clone();
+            spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    let v = vec![0u8; 4096];
+                    drop(v);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // The inherited environment must not be read by the child while another thread changes
+    // it. Tests that read the environment are serial, so they can't run alongside this one.
+    let setenv = {
+        let done = done.clone();
+        spawn(move || {
+            let mut i = 0u64;
+            while !done.load(Ordering::Relaxed) {
+                set_var("MEMFD_EXEC_TEST_THREADS", i.to_string());
+                i += 1;
+            }
+        })
+    };
+
+    let spawners = (0..16)
+        .map(|i| {
+            let cat_contents = cat_contents.clone();
+            spawn(move || {
+                for j in 0..16 {
+                    let mut cat = MemFdExecutable::new("cat", cat_contents.clone())
+                        .stdin(Stdio::piped())
+                        .stdout(Stdio::piped())
+                        .spawn()
+                        .expect("Failed to run cat");
+
+                    let message = format!("{} {}", i, j);
+                    cat.stdin
+                        .take()
+                        .expect("Failed to open stdin")
+                        .write_all(message.as_bytes())
+                        .expect("Failed to write to cat stdin");
+
+                    let output = cat.wait_with_output().expect("Failed to run cat");
+                    assert_eq!(output.stdout, message.as_bytes());
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for spawner in spawners {
+        spawner.join().expect("Failed to join spawner thread");
+    }
+    done.store(true, Ordering::Relaxed);
+    for allocator in allocators {
+        allocator.join().expect("Failed to join allocator thread");
+    }
+    setenv.join().expect("Failed to join setenv thread");
+    remove_var("MEMFD_EXEC_TEST_THREADS");
+}
+
+fn run_env(configure: impl FnOnce(&mut MemFdExecutable)) -> String {
+    let env_contents = read("/usr/bin/env").expect("Could not read /usr/bin/env");
+    let mut env = MemFdExecutable::new("env", &env_contents);
+    configure(env.stdout(Stdio::piped()));
+    let output = env.output().expect("Failed to run env");
+    String::from_utf8(output.stdout).expect("env output was not UTF-8")
+}
+
+#[test]
+#[serial]
+fn test_env_inherit() {
+    let path = std::env::var("PATH").expect("PATH is not set");
+    let stdout = run_env(|_| {});
+    assert!(
+        stdout.lines().any(|l| l == format!("PATH={}", path)),
+        "PATH was not inherited: {}",
+        stdout
+    );
+
+    let stdout = run_env(|env| {
+        env.env("MEMFD_EXEC_TEST", "1");
+    });
+    assert!(stdout.lines().any(|l| l == format!("PATH={}", path)));
+    assert!(stdout.lines().any(|l| l == "MEMFD_EXEC_TEST=1"));
+}
+
+#[test]
+fn test_env_clear() {
+    assert_eq!(
+        run_env(|env| {
+            env.env_policy(EnvPolicy::Clear);
+        }),
+        ""
+    );
+    assert_eq!(
+        run_env(|env| {
+            env.env_policy(EnvPolicy::Clear).env("MEMFD_EXEC_TEST", "1");
+        }),
+        "MEMFD_EXEC_TEST=1\n"
+    );
+}
+
+#[test]
+#[serial]
+fn test_env_allowlist() {
+    let path = std::env::var("PATH").expect("PATH is not set");
+    let stdout = run_env(|env| {
+        env.env_policy(EnvPolicy::Allowlist(vec!["PATH".into()]))
+            .env("MEMFD_EXEC_TEST", "1");
+    });
+    assert_eq!(stdout, format!("MEMFD_EXEC_TEST=1\nPATH={}\n", path));
+}
+
+#[test]
+fn test_exit_status() {
+    let sh_contents = read("/bin/sh").expect("Could not read /bin/sh");
+    let status = MemFdExecutable::new("sh", &sh_contents)
+        .arg("-c")
+        .arg("exit 3")
+        .status()
+        .expect("Failed to run sh");
+    assert_eq!(status.code(), Some(3));
+
+    let status = MemFdExecutable::new("sh", &sh_contents)
+        .arg("-c")
+        .arg("kill -TERM $$")
+        .status()
+        .expect("Failed to run sh");
+    assert_eq!(status.code(), None);
+    assert_eq!(status.signal(), Some(libc::SIGTERM));
+}
+
+#[test]
+fn test_pidfd() {
+    let sleep_contents = read("/bin/sleep").expect("Could not read /bin/sleep");
+    let mut sleep = MemFdExecutable::new("sleep", &sleep_contents)
+        .arg("10")
+        .spawn()
+        .expect("Failed to run sleep");
+
+    let poll_pidfd = |child: &memfd_exec::Child, timeout: i32| {
+        let mut fds = [libc::pollfd {
+            fd: child.pidfd().expect("No pidfd").as_fd().as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout) }
+    };
+
+    assert_eq!(poll_pidfd(&sleep, 0), 0, "pidfd readable while running");
+    assert_eq!(sleep.try_wait().expect("Failed to try_wait"), None);
+
+    sleep.kill().expect("Failed to kill sleep");
+    assert_eq!(poll_pidfd(&sleep, 5000), 1, "pidfd not readable after kill");
+
+    let status = sleep.wait().expect("Failed to wait for sleep");
+    assert_eq!(status.signal(), Some(libc::SIGKILL));
+    assert!(sleep.kill().is_err(), "Killed a reaped process");
+}
+
+#[test]
+fn test_signal() {
+    let sleep_contents = read("/bin/sleep").expect("Could not read /bin/sleep");
+    let mut sleep = MemFdExecutable::new("sleep", &sleep_contents)
+        .arg("10")
+        .spawn()
+        .expect("Failed to run sleep");
+
+    sleep.signal(libc::SIGUSR1).expect("Failed to signal sleep");
+    let status = sleep.wait().expect("Failed to wait for sleep");
+    assert_eq!(status.signal(), Some(libc::SIGUSR1));
+    assert!(
+        sleep.signal(libc::SIGUSR1).is_err(),
+        "Signalled a reaped process"
+    );
+}
+
+#[test]
+fn test_terminate() {
+    let sleep_contents = read("/bin/sleep").expect("Could not read /bin/sleep");
+    let mut sleep = MemFdExecutable::new("sleep", &sleep_contents)
+        .arg("10")
+        .spawn()
+        .expect("Failed to run sleep");
+
+    let status = sleep
+        .terminate(Duration::from_secs(5))
+        .expect("Failed to terminate sleep");
+    assert_eq!(status.signal(), Some(libc::SIGTERM));
+
+    // Terminating again just returns the status we already reaped
+    assert_eq!(
+        sleep
+            .terminate(Duration::from_secs(5))
+            .expect("Failed to terminate sleep"),
+        status
+    );
+}
+
+#[test]
+fn test_terminate_unbounded() {
+    let sleep_contents = read("/bin/sleep").expect("Could not read /bin/sleep");
+    let mut sleep = MemFdExecutable::new("sleep", &sleep_contents)
+        .arg("10")
+        .spawn()
+        .expect("Failed to run sleep");
+
+    // A grace period with no deadline waits for as long as the process takes to exit
+    let status = sleep
+        .terminate(Duration::MAX)
+        .expect("Failed to terminate sleep");
+    assert_eq!(status.signal(), Some(libc::SIGTERM));
+}
+
+#[test]
+fn test_terminate_escalates() {
+    let sh_contents = read("/bin/sh").expect("Could not read /bin/sh");
+    let mut stubborn = MemFdExecutable::new("sh", &sh_contents)
+        .arg("-c")
+        .arg("trap '' TERM; exec sleep 10")
+        .spawn()
+        .expect("Failed to run sh");
+
+    // Give the shell time to install its trap before we signal it
+    sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    let status = stubborn
+        .terminate(Duration::from_millis(200))
+        .expect("Failed to terminate sh");
+    assert_eq!(status.signal(), Some(libc::SIGKILL));
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_wait_timeout() {
+    let sleep_contents = read("/bin/sleep").expect("Could not read /bin/sleep");
+    let mut sleep = MemFdExecutable::new("sleep", &sleep_contents)
+        .arg("10")
+        .spawn()
+        .expect("Failed to run sleep");
+
+    let start = Instant::now();
+    assert_eq!(
+        sleep
+            .wait_timeout(Duration::from_millis(200))
+            .expect("Failed to wait for sleep"),
+        None
+    );
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    sleep.kill().expect("Failed to kill sleep");
+    let status = sleep
+        .wait_timeout(Duration::from_secs(5))
+        .expect("Failed to wait for sleep")
+        .expect("sleep did not exit after being killed");
+    assert_eq!(status.signal(), Some(libc::SIGKILL));
+}
+
+#[test]
+fn test_wait_with_output_timeout() {
+    let sh_contents = read("/bin/sh").expect("Could not read /bin/sh");
+    let mut sh = MemFdExecutable::new("sh", &sh_contents)
+        .arg("-c")
+        .arg("echo out; echo err >&2; exec sleep 10")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to run sh");
+
+    let err = sh
+        .wait_with_output_timeout(Duration::from_millis(500))
+        .expect_err("sh exited early");
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    let partial = err
+        .into_inner()
+        .expect("Error has no inner error")
+        .downcast::<OutputTimeout>()
+        .expect("Error was not an OutputTimeout");
+    assert_eq!(partial.stdout, b"out\n");
+    assert_eq!(partial.stderr, b"err\n");
+
+    sh.kill().expect("Failed to kill sh");
+    let output = sh
+        .wait_with_output_timeout(Duration::from_secs(5))
+        .expect("Failed to wait for sh");
+    assert_eq!(output.status.signal(), Some(libc::SIGKILL));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_wait_with_output_timeout_exits() {
+    let echo_contents = read("/bin/echo").expect("Could not read /bin/echo");
+    let output = MemFdExecutable::new("echo", &echo_contents)
+        .arg("hello")
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run echo")
+        .wait_with_output_timeout(Duration::from_secs(5))
+        .expect("Failed to wait for echo");
+    assert_eq!(output.stdout, b"hello\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_wait_timeout_unbounded() {
+    let sh_contents = read("/bin/sh").expect("Could not read /bin/sh");
+    let mut sh = MemFdExecutable::new("sh", &sh_contents)
+        .arg("-c")
+        .arg("sleep 0.2; echo out; exit 3")
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run sh");
+    let output = sh
+        .wait_with_output_timeout(Duration::MAX)
+        .expect("Failed to wait for sh");
+    assert_eq!(output.stdout, b"out\n");
+    assert_eq!(output.status.code(), Some(3));
+
+    let sleep_contents = read("/bin/sleep").expect("Could not read /bin/sleep");
+    let mut sleep = MemFdExecutable::new("sleep", &sleep_contents)
+        .arg("0.2")
+        .spawn()
+        .expect("Failed to run sleep");
+    let status = sleep
+        .wait_timeout(Duration::MAX)
+        .expect("Failed to wait for sleep")
+        .expect("sleep did not exit");
+    assert_eq!(status.code(), Some(0));
+}
+
+#[test]
+fn test_pre_exec() {
+    let sh_contents = read("/bin/sh").expect("Could not read /bin/sh");
+    let mut sh = MemFdExecutable::new("sh", &sh_contents);
+    sh.arg("-c").arg("ulimit -n").stdout(Stdio::piped());
+    unsafe {
+        sh.pre_exec(|| {
+            let limit = libc::rlimit {
+                rlim_cur: 123,
+                rlim_max: 123,
+            };
+            if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) != 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let output = sh.output().expect("Failed to run sh");
+    assert_eq!(output.stdout, b"123\n");
+}
+
+#[test]
+fn test_pre_exec_error() {
+    let true_contents = read("/bin/true").expect("Could not read /bin/true");
+    let mut true_cmd = MemFdExecutable::new("true", &true_contents);
+    unsafe {
+        true_cmd.pre_exec(|| Err(Error::from_raw_os_error(libc::EPERM)));
+    }
+    let err = true_cmd
+        .spawn()
+        .expect_err("Spawned despite a failing pre_exec");
+
+    let spawn_err = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<SpawnError>())
+        .expect("Error was not a SpawnError");
+    assert_eq!(spawn_err.stage(), ExecStage::PreExec);
+    assert_eq!(spawn_err.raw_os_error(), Some(libc::EPERM));
+}
+
+#[test]
+fn test_uid_gid_groups() {
+    if unsafe { libc::geteuid() } != 0 {
+        // Switching users needs root
+        return;
+    }
+
+    let id_contents = read("/usr/bin/id").expect("Could not read /usr/bin/id");
+    let run_id = |flag: &str| {
+        let output = MemFdExecutable::new("id", &id_contents)
+            .arg(flag)
+            .groups(&[65534, 100])
+            .gid(65534)
+            .uid(65534)
+            .stdout(Stdio::piped())
+            .output()
+            .expect("Failed to run id");
+        assert_eq!(output.status.code(), Some(0));
+        String::from_utf8(output.stdout).expect("id output was not UTF-8")
+    };
+
+    assert_eq!(run_id("-u"), "65534\n");
+    assert_eq!(run_id("-g"), "65534\n");
+    let mut groups = run_id("-G")
+        .split_whitespace()
+        .map(|g| g.parse::<u32>().expect("Bad group id"))
+        .collect::<Vec<_>>();
+    groups.sort();
+    assert_eq!(groups, [100, 65534]);
+}
+
+#[test]
+fn test_groups_error() {
+    // Far more groups than any kernel allows
+    let groups = vec![65534; 1 << 17];
+    let true_contents = read("/bin/true").expect("Could not read /bin/true");
+    let err = MemFdExecutable::new("true", &true_contents)
+        .groups(&groups)
+        .spawn()
+        .expect_err("Spawned with too many groups");
+
+    let spawn_err = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<SpawnError>())
+        .expect("Error was not a SpawnError");
+    assert_eq!(spawn_err.stage(), ExecStage::SetGroups);
+}
+
+/// Run `cat /proc/self/stat` and return the pid, process group and session of the cat
+fn run_stat(exe: &mut MemFdExecutable) -> (u32, u32, u32) {
+    let output = exe
+        .arg("/proc/self/stat")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run cat");
+    assert_eq!(output.status.code(), Some(0));
+    let stat = String::from_utf8(output.stdout).expect("stat was not UTF-8");
+    // pid (comm) state ppid pgrp session ...
+    let (pid, rest) = stat.split_once(' ').expect("Bad stat");
+    let fields = rest.rsplit_once(") ").expect("Bad stat").1;
+    let fields = fields.split(' ').collect::<Vec<_>>();
+    (
+        pid.parse().expect("Bad pid"),
+        fields[2].parse().expect("Bad pgrp"),
+        fields[3].parse().expect("Bad session"),
+    )
+}
+
+#[test]
+fn test_process_group() {
+    let cat_contents = read("/bin/cat").expect("Could not read /bin/cat");
+    let (pid, pgrp, session) =
+        run_stat(MemFdExecutable::new("cat", &cat_contents).process_group(0));
+    assert_eq!(pgrp, pid);
+    assert_ne!(session, pid);
+}
+
+#[test]
+fn test_setsid() {
+    let cat_contents = read("/bin/cat").expect("Could not read /bin/cat");
+    let (pid, pgrp, session) = run_stat(MemFdExecutable::new("cat", &cat_contents).setsid(true));
+    assert_eq!(pgrp, pid);
+    assert_eq!(session, pid);
+}
+
+#[test]
+fn test_setsid_with_process_group() {
+    let cat_contents = read("/bin/cat").expect("Could not read /bin/cat");
+    let err = MemFdExecutable::new("cat", &cat_contents)
+        .setsid(true)
+        .process_group(0)
+        .spawn()
+        .expect_err("Spawned with both setsid and process_group");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    // This is caught before forking, not by setsid failing in the child
+    assert!(err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<SpawnError>())
+        .is_none());
+}
+
+#[test]
+fn test_kill_group() {
+    let sh_contents = read("/bin/sh").expect("Could not read /bin/sh");
+    let mut sh = MemFdExecutable::new("sh", &sh_contents)
+        .arg("-c")
+        .arg("sleep 60 & echo $!; wait")
+        .process_group(0)
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run sh");
+
+    let mut line = String::new();
+    BufReader::new(sh.stdout.take().expect("Failed to open stdout"))
+        .read_line(&mut line)
+        .expect("Failed to read sleep pid");
+    let sleep_pid: i32 = line.trim().parse().expect("Bad sleep pid");
+
+    sh.kill_group(libc::SIGKILL).expect("Failed to kill group");
+    assert_eq!(
+        sh.wait().expect("Failed to wait").signal(),
+        Some(libc::SIGKILL)
+    );
+
+    // Once reaped, the group id may be recycled, so it can't be signalled any more
+    let err = sh
+        .kill_group(libc::SIGKILL)
+        .expect_err("Signalled the group of a reaped process");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    // The orphaned sleep is reparented, so it may linger as a zombie until it is reaped
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match read_to_string(format!("/proc/{sleep_pid}/stat")) {
+            Err(_) => break,
+            Ok(stat) if stat.rsplit_once(") ").unwrap().1.starts_with('Z') => break,
+            Ok(_) => {
+                assert!(Instant::now() < deadline, "sleep survived kill_group");
+                sleep(Duration::from_millis(10));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_kill_group_without_group() {
+    let sleep_contents = read("/bin/sleep").expect("Could not read /bin/sleep");
+    let mut sleep = MemFdExecutable::new("sleep", &sleep_contents)
+        .arg("60")
+        .spawn()
+        .expect("Failed to run sleep");
+
+    // The child shares our process group, which must not be signalled
+    let err = sleep
+        .kill_group(libc::SIGKILL)
+        .expect_err("Signalled the parent's process group");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    sleep.kill().expect("Failed to kill sleep");
+    sleep.wait().expect("Failed to wait for sleep");
+}
+
+#[test]
+fn test_parent_death_signal() {
+    let sleep_contents = read("/bin/sleep").expect("Could not read /bin/sleep");
+    // The signal is sent when the thread that spawned the child exits
+    let mut sleep = spawn(move || {
+        MemFdExecutable::new("sleep", &sleep_contents)
+            .arg("60")
+            .parent_death_signal(libc::SIGKILL)
+            .spawn()
+            .expect("Failed to run sleep")
+    })
+    .join()
+    .expect("Spawning thread panicked");
+
+    let status = sleep
+        .wait_timeout(Duration::from_secs(5))
+        .expect("Failed to wait for sleep")
+        .expect("sleep survived its parent");
+    assert_eq!(status.signal(), Some(libc::SIGKILL));
+}
+
+#[test]
+fn test_kill_on_drop() {
+    let sleep_contents = read("/bin/sleep").expect("Could not read /bin/sleep");
+    let spawn_sleep = || {
+        MemFdExecutable::new("sleep", &sleep_contents)
+            .arg("60")
+            .spawn()
+            .expect("Failed to run sleep")
+    };
+
+    let mut sleep = spawn_sleep();
+    sleep.kill_on_drop(true);
+    let proc = PathBuf::from(format!("/proc/{}", sleep.id()));
+    drop(sleep);
+    // The child was reaped, so it doesn't even linger as a zombie
+    assert!(!proc.exists());
+
+    // By default, dropping the handle leaves the child running
+    let sleep = spawn_sleep();
+    let pid = sleep.id() as i32;
+    drop(sleep);
+    unsafe {
+        assert_eq!(libc::kill(pid, libc::SIGKILL), 0);
+        assert_eq!(libc::waitpid(pid, std::ptr::null_mut(), 0), pid);
+    }
+}
+
+/// Create an unlinked temporary file holding `contents`, ready to be read from the start
+fn temp_file_with(contents: &[u8]) -> File {
+    let mut file = tempfile().expect("Failed to create temp file");
+    file.write_all(contents).expect("Failed to write temp file");
+    file.seek(SeekFrom::Start(0))
+        .expect("Failed to seek temp file");
+    file
+}
+
+#[test]
+fn test_fd_map() {
+    let sh_contents = read("/bin/sh").expect("Could not read /bin/sh");
+
+    // Each file is mapped to the other's number, so the sources and targets overlap
+    let a = temp_file_with(b"a\n");
+    let b = temp_file_with(b"b\n");
+    let (a_fd, b_fd) = (a.as_raw_fd(), b.as_raw_fd());
+    let mut sh = MemFdExecutable::new("sh", &sh_contents);
+    sh.arg("-c")
+        .arg(format!("cat <&{a_fd}; cat <&{b_fd}"))
+        .fd_map(a_fd, b)
+        .fd_map(b_fd, a)
+        .stdout(Stdio::piped());
+    // Lots of low mappings, so that some land on the numbers the memfd and the error pipe
+    // would otherwise have
+    for fd in 3..32 {
+        if fd != a_fd && fd != b_fd {
+            sh.fd_map(fd, Stdio::null());
+        }
+    }
+    let output = sh.output().expect("Failed to run sh");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"b\na\n");
+}
+
+#[test]
+fn test_fd_map_inherit() {
+    // std opens files close-on-exec, so the child only sees it if it's mapped
+    let file = temp_file_with(b"inherited\n");
+    let fd = file.as_raw_fd();
+    let cat_contents = read("/bin/cat").expect("Could not read /bin/cat");
+    let output = MemFdExecutable::new("cat", &cat_contents)
+        .arg(format!("/proc/self/fd/{fd}"))
+        .fd_map(fd, Stdio::inherit())
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run cat");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"inherited\n");
+}
+
+#[test]
+fn test_fd_map_invalid() {
+    let true_contents = read("/bin/true").expect("Could not read /bin/true");
+    let err = MemFdExecutable::new("true", &true_contents)
+        .fd_map(1, Stdio::null())
+        .spawn()
+        .expect_err("Mapped stdout with fd_map");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let err = MemFdExecutable::new("true", &true_contents)
+        .fd_map(3, Stdio::piped())
+        .spawn()
+        .expect_err("Mapped a new pipe with fd_map");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_close_other_fds() {
+    let sh_contents = read("/bin/sh").expect("Could not read /bin/sh");
+    let mapped = temp_file_with(b"");
+    // A descriptor opened without O_CLOEXEC, which would otherwise leak into the child
+    let leaked = unsafe { libc::dup(mapped.as_raw_fd()) };
+    assert!(leaked >= 0);
+
+    let is_open_in_child = |fd: i32, close: bool| {
+        let status = MemFdExecutable::new("sh", &sh_contents)
+            .arg("-c")
+            .arg(format!("test -e /proc/self/fd/{fd}"))
+            .fd_map(3, mapped.try_clone().expect("Failed to clone file"))
+            .close_other_fds(close)
+            .status()
+            .expect("Failed to run sh");
+        status.code() == Some(0)
+    };
+
+    assert!(is_open_in_child(leaked, false));
+    assert!(!is_open_in_child(leaked, true));
+    assert!(is_open_in_child(3, true));
+    assert!(is_open_in_child(2, true));
+
+    unsafe { libc::close(leaked) };
+}
+
+const GREET_SCRIPT: &[u8] = b"#!/bin/sh\necho hello $1\n";
+
+#[test]
+fn test_shebang_script() {
+    let output = MemFdExecutable::new("greet", GREET_SCRIPT)
+        .arg("world")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run script");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"hello world\n");
+
+    // The script's memfd must survive closing everything else
+    let output = MemFdExecutable::from_image(
+        "greet",
+        &MemFdImage::new(GREET_SCRIPT).expect("Failed to create image"),
+    )
+    .arg("image")
+    .close_other_fds(true)
+    .stdout(Stdio::piped())
+    .output()
+    .expect("Failed to run script");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"hello image\n");
+}
+
+#[test]
+fn test_shebang_args() {
+    // Everything after the interpreter is one argument, like the kernel passes it
+    let output = MemFdExecutable::new("script", b"#!  /bin/sh  -e \t\nfalse\necho unreachable\n")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run script");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(output.stdout, b"");
+
+    let err = MemFdExecutable::new("script", b"#! \necho hello\n")
+        .spawn()
+        .expect_err("Ran a script without an interpreter");
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_interpreter() {
+    let output = MemFdExecutable::new("script", b"echo \"$0\" \"$1\"; false; echo unreachable")
+        .interpreter("/bin/sh", ["-e"])
+        .arg("arg")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run script");
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).expect("Output was not UTF-8");
+    let (script, arg) = stdout.trim_end().split_once(' ').expect("Bad output");
+    assert!(script.starts_with("/proc/self/fd/"));
+    assert_eq!(arg, "arg");
+}
+
+#[test]
+fn test_interpreter_missing() {
+    let err = MemFdExecutable::new("script", b"echo hello")
+        .interpreter("/nonexistent/interpreter", [] as [&str; 0])
+        .spawn()
+        .expect_err("Ran a missing interpreter");
+    let spawn_err = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<SpawnError>())
+        .expect("Error was not a SpawnError");
+    assert_eq!(spawn_err.stage(), ExecStage::Interpreter);
+    assert_eq!(spawn_err.raw_os_error(), Some(libc::ENOENT));
+}
+
+#[test]
+fn test_inspect() {
+    let true_contents = read("/bin/true").expect("Could not read /bin/true");
+    let inspection = MemFdExecutable::new("true", &true_contents)
+        .inspect()
+        .expect("Failed to inspect true");
+    assert!(!inspection.is_static());
+    assert!(inspection
+        .interpreter
+        .as_ref()
+        .is_some_and(|interpreter| interpreter.exists()));
+    assert!(inspection.missing().is_empty(), "{inspection:?}");
+    let libc = inspection
+        .libraries
+        .iter()
+        .find(|library| library.name == "libc.so.6")
+        .expect("true doesn't need libc");
+    assert!(libc.path.as_ref().is_some_and(|path| path.exists()));
+
+    assert_eq!(
+        MemFdExecutable::new("garbage", b"not an elf")
+            .inspect()
+            .expect_err("Inspected garbage")
+            .kind(),
+        ErrorKind::InvalidData
+    );
+}
+
+/// A copy of the 64-bit `code` with its `p_type` segment emptied and moved past the end of
+/// the file, which `validate` allows for segments without file contents
+fn empty_segment(mut code: Vec<u8>, p_type: u32) -> Vec<u8> {
+    let u64_at = |code: &[u8], at: usize| u64::from_ne_bytes(code[at..at + 8].try_into().unwrap());
+    let phoff = u64_at(&code, 32) as usize;
+    let phnum = u16::from_ne_bytes([code[56], code[57]]) as usize;
+    let phdr = (0..phnum)
+        .map(|index| phoff + index * 56)
+        .find(|&at| u32::from_ne_bytes(code[at..at + 4].try_into().unwrap()) == p_type)
+        .expect("Segment not found");
+    code[phdr + 8..phdr + 16].copy_from_slice(&(u64::MAX - 1).to_ne_bytes());
+    code[phdr + 32..phdr + 40].copy_from_slice(&0u64.to_ne_bytes());
+    code
+}
+
+#[test]
+fn test_inspect_empty_segments() {
+    let true_contents = read("/bin/true").expect("Could not read /bin/true");
+    for p_type in [libc::PT_INTERP, libc::PT_DYNAMIC] {
+        let err = MemFdExecutable::new("true", empty_segment(true_contents.clone(), p_type))
+            .inspect()
+            .expect_err("Inspected an empty segment");
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            err.get_ref()
+                .and_then(|e| e.downcast_ref::<InvalidExecutable>()),
+            Some(&InvalidExecutable::BadDynamicSegment)
+        );
+    }
+}
+
+#[test]
+fn test_inspect_missing() {
+    // Rename true's libc dependency to a library that doesn't exist
+    let mut true_contents = read("/bin/true").expect("Could not read /bin/true");
+    let at = true_contents
+        .windows(b"libc.so.6\0".len())
+        .position(|name| name == b"libc.so.6\0")
+        .expect("true doesn't need libc");
+    true_contents[at..at + 4].copy_from_slice(b"libq");
+
+    let mut exe = MemFdExecutable::new("true", &true_contents);
+    let inspection = exe.inspect().expect("Failed to inspect true");
+    assert_eq!(inspection.missing(), ["libq.so.6"]);
+
+    // The library is found once it's in the program's LD_LIBRARY_PATH
+    let libc = MemFdExecutable::new("true", read("/bin/true").unwrap())
+        .inspect()
+        .unwrap()
+        .libraries
+        .into_iter()
+        .find_map(|library| library.path.filter(|_| library.name == "libc.so.6"))
+        .expect("libc not found");
+    let dir = tempdir().expect("Failed to create temp dir");
+    symlink(libc, dir.path().join("libq.so.6")).expect("Failed to link libc");
+    let inspection = exe
+        .env("LD_LIBRARY_PATH", dir.path())
+        .inspect()
+        .expect("Failed to inspect true");
+    assert!(inspection.missing().is_empty(), "{inspection:?}");
+    let libq = &inspection.libraries[0];
+    assert_eq!(libq.name, "libq.so.6");
+    assert_eq!(libq.path, Some(dir.path().join("libq.so.6")));
+}
+
+const GREET_LIBRARY_CODE: &[u8] = b"#include <stdio.h>
+void greet(const char *name) { printf(\"hello %s\\n\", name); }
+";
+
+const GREET_PROGRAM_CODE: &[u8] = b"void greet(const char *name);
+int main(int argc, char **argv) { greet(argv[1]); return 0; }
+";
+
+/// Compile C `code` from stdin with clang and the given arguments
+fn compile(args: &[&OsStr], code: &'static [u8]) {
+    let mut clang = Command::new("clang")
+        .args(["-x", "c", "-", "-x", "none"])
+        .args(args)
+        .stdin(ProcessStdio::piped())
+        .stdout(ProcessStdio::piped())
+        .stderr(ProcessStdio::piped())
+        .spawn()
+        .expect("Failed to run clang");
+
+    let mut clang_stdin = clang.stdin.take().expect("Failed to open stdin");
+    spawn(move || {
+        clang_stdin
+            .write_all(code)
+            .expect("Could not write to clang stdin");
+    });
+
+    let output = clang.wait_with_output().expect("Failed to run clang");
+    assert!(
+        output.status.success(),
+        "Failed to compile:\nstdout: {}\nstderr: {}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Build a program and the `libgreet.so.1` it needs, returning their code
+fn build_greet() -> (Vec<u8>, Vec<u8>) {
+    let dir = tempdir().expect("Failed to create temp dir");
+    let library = dir.path().join("libgreet.so.1");
+    let program = dir.path().join("greet");
+    compile(
+        &[
+            "-shared".as_ref(),
+            "-fPIC".as_ref(),
+            "-Wl,-soname,libgreet.so.1".as_ref(),
+            "-o".as_ref(),
+            library.as_ref(),
+        ],
+        GREET_LIBRARY_CODE,
+    );
+    compile(
+        &["-o".as_ref(), program.as_ref(), library.as_ref()],
+        GREET_PROGRAM_CODE,
+    );
+    // The directory is removed here, so the library can only come from memory
+    (
+        read(program).expect("Could not read greet"),
+        read(library).expect("Could not read libgreet"),
+    )
+}
+
+#[test]
+fn test_library() {
+    let (program, library) = build_greet();
+
+    let output = MemFdExecutable::new("greet", &program)
+        .arg("world")
+        .library("libgreet.so.1", &library)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .expect("Failed to run greet");
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+    assert_eq!(output.stdout, b"hello world\n");
+
+    // Without the library the loader gives up
+    let output = MemFdExecutable::new("greet", &program)
+        .stderr(Stdio::piped())
+        .output()
+        .expect("Failed to run greet");
+    assert_eq!(output.status.code(), Some(127));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("libgreet.so.1"));
+}
+
+#[test]
+fn test_library_argv0() {
+    let (_, library) = build_greet();
+    let sh_contents = read("/bin/sh").expect("Could not read /bin/sh");
+
+    // Bundling an unused library doesn't change what the program sees
+    let output = MemFdExecutable::new("greeter", &sh_contents)
+        .args(["-c", "echo $0 $1", "x", "y"])
+        .library("libgreet.so.1", &library)
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run sh");
+    assert_eq!(output.stdout, b"x y\n");
+}
+
+#[test]
+fn test_library_invalid() {
+    let (program, library) = build_greet();
+
+    let err = MemFdExecutable::new("greet", &program)
+        .library("libgreet.so", &library)
+        .spawn()
+        .expect_err("Bundled a library under the wrong name");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("libgreet.so.1"), "{err}");
+
+    let err = MemFdExecutable::new("greet", GREET_SCRIPT)
+        .library("libgreet.so.1", &library)
+        .spawn()
+        .expect_err("Bundled a library with a script");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_library_only_needed() {
+    // Bundle the host's libc, which cat needs, and libm, which it doesn't
+    let cat_contents = read("/bin/cat").expect("Could not read /bin/cat");
+    let libc = MemFdExecutable::new("cat", &cat_contents)
+        .inspect()
+        .expect("Failed to inspect cat")
+        .libraries
+        .into_iter()
+        .find_map(|library| library.path.filter(|_| library.name == "libc.so.6"))
+        .expect("libc not found");
+    let libm = libc.with_file_name("libm.so.6");
+    let output = MemFdExecutable::new("cat", &cat_contents)
+        .arg("/proc/self/maps")
+        .library("libc.so.6", read(&libc).expect("Could not read libc"))
+        .library("libm.so.6", read(&libm).expect("Could not read libm"))
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run cat");
+    assert_eq!(output.status.code(), Some(0));
+    let maps = String::from_utf8(output.stdout).expect("Maps were not UTF-8");
+
+    // cat and the bundled libc are mapped from memfds, and libm isn't loaded at all
+    let memfds = maps
+        .lines()
+        .filter(|line| line.contains("/memfd:"))
+        .filter_map(|line| line.split_whitespace().nth(4))
+        .collect::<HashSet<_>>();
+    assert_eq!(memfds.len(), 2, "{maps}");
+    assert!(!maps.contains("libc.so.6"), "{maps}");
+    assert!(!maps.contains("libm.so.6"), "{maps}");
+}
+
+#[test]
+fn test_library_bad_name() {
+    // Library names are file names in the directory the loader searches
+    let true_contents = read("/bin/true").expect("Could not read /bin/true");
+    for name in ["", "..", "../libc.so.6"] {
+        let err = MemFdExecutable::new("true", &true_contents)
+            .library(name, &true_contents)
+            .spawn()
+            .expect_err("Bundled a library with a bad name");
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn test_library_empty_segments() {
+    // A malformed library fails the spawn in the parent rather than panicking
+    let true_contents = read("/bin/true").expect("Could not read /bin/true");
+    for p_type in [libc::PT_INTERP, libc::PT_DYNAMIC] {
+        let err = MemFdExecutable::new("true", &true_contents)
+            .library("libempty.so", empty_segment(true_contents.clone(), p_type))
+            .spawn()
+            .expect_err("Bundled a library with an empty segment");
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            err.get_ref()
+                .and_then(|e| e.downcast_ref::<InvalidExecutable>()),
+            Some(&InvalidExecutable::BadDynamicSegment)
+        );
+    }
+}
+
+#[test]
+fn test_inspect_rpath_chain() {
+    // greet -> libouter.so -> libmiddle.so -> libgreet.so.1, where libgreet is only in the
+    // RPATH of libouter, which loaded the library that needs it
+    let (program_dir, outer_dir, greet_dir) =
+        (tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap());
+    let greet = greet_dir.path().join("libgreet.so.1");
+    let middle = outer_dir.path().join("libmiddle.so");
+    let outer = program_dir.path().join("libouter.so");
+    let program = program_dir.path().join("greet");
+    compile(
+        &[
+            "-shared".as_ref(),
+            "-fPIC".as_ref(),
+            "-Wl,-soname,libgreet.so.1".as_ref(),
+            "-o".as_ref(),
+            greet.as_ref(),
+        ],
+        GREET_LIBRARY_CODE,
+    );
+    compile(
+        &[
+            "-shared".as_ref(),
+            "-fPIC".as_ref(),
+            "-Wl,-soname,libmiddle.so".as_ref(),
+            "-o".as_ref(),
+            middle.as_ref(),
+            greet.as_ref(),
+        ],
+        b"void greet(const char *name);
+void middle(const char *name) { greet(name); }
+",
+    );
+    let mut rpath = OsString::from("-Wl,--disable-new-dtags,-rpath,");
+    rpath.push(outer_dir.path());
+    rpath.push(":");
+    rpath.push(greet_dir.path());
+    compile(
+        &[
+            "-shared".as_ref(),
+            "-fPIC".as_ref(),
+            "-Wl,-soname,libouter.so".as_ref(),
+            &rpath,
+            "-o".as_ref(),
+            outer.as_ref(),
+            middle.as_ref(),
+        ],
+        b"void middle(const char *name);
+void outer(const char *name) { middle(name); }
+",
+    );
+    let mut rpath = OsString::from("-Wl,--disable-new-dtags,-rpath,");
+    rpath.push(program_dir.path());
+    // The linker checks the whole chain too, but doesn't follow RPATHs to do it
+    rpath.push(",-rpath-link,");
+    rpath.push(outer_dir.path());
+    rpath.push(":");
+    rpath.push(greet_dir.path());
+    compile(
+        &["-o".as_ref(), program.as_ref(), &rpath, outer.as_ref()],
+        b"void outer(const char *name);
+int main(int argc, char **argv) { outer(argv[1]); return 0; }
+",
+    );
+
+    let program = read(program).expect("Could not read greet");
+    let inspection = MemFdExecutable::new("greet", &program)
+        .inspect()
+        .expect("Failed to inspect greet");
+    assert!(inspection.missing().is_empty(), "{inspection:?}");
+    for (name, path) in [
+        ("libouter.so", outer),
+        ("libmiddle.so", middle),
+        ("libgreet.so.1", greet),
+    ] {
+        let library = inspection
+            .libraries
+            .iter()
+            .find(|library| library.name == name);
+        assert_eq!(library.and_then(|library| library.path.clone()), Some(path));
+    }
+
+    // The real loader agrees
+    let output = MemFdExecutable::new("greet", &program)
+        .arg("world")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run greet");
+    assert_eq!(output.stdout, b"hello world\n");
+}
+
+/// Read echo, with its loader renamed to one that doesn't exist, and its real loader
+fn echo_with_missing_loader() -> (Vec<u8>, PathBuf) {
+    let mut echo_contents = read("/bin/echo").expect("Could not read /bin/echo");
+    let loader = MemFdExecutable::new("echo", &echo_contents)
+        .inspect()
+        .expect("Failed to inspect echo")
+        .interpreter
+        .expect("echo has no loader");
+    let name = loader.as_os_str().as_encoded_bytes();
+    let at = echo_contents
+        .windows(name.len())
+        .position(|window| window == name)
+        .expect("echo doesn't name its loader");
+    *echo_contents[at..at + name.len()].last_mut().unwrap() = b'@';
+    (echo_contents, loader)
+}
+
+#[test]
+fn test_loader() {
+    let (echo_contents, loader) = echo_with_missing_loader();
+
+    let err = MemFdExecutable::new("echo", &echo_contents)
+        .spawn()
+        .expect_err("Ran echo without its loader");
+    let err = err.get_ref().unwrap().downcast_ref::<SpawnError>().unwrap();
+    assert_eq!(err.stage(), ExecStage::Fexecve);
+
+    let output = MemFdExecutable::new("echo", &echo_contents)
+        .loader(loader.as_path())
+        .args(["hello", "world"])
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run echo");
+    assert_eq!(output.stdout, b"hello world\n");
+
+    // The program's argv[0] and environment are passed through
+    let sh_contents = read("/bin/sh").expect("Could not read /bin/sh");
+    let output = MemFdExecutable::new("greeter", &sh_contents)
+        .loader(loader.as_path())
+        .args(["-c", "echo $0 $GREETING"])
+        .env("GREETING", "hello")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run sh");
+    assert_eq!(output.stdout, b"greeter hello\n");
+}
+
+#[test]
+fn test_loader_from_memory() {
+    let (echo_contents, loader) = echo_with_missing_loader();
+    let loader_contents = read(loader).expect("Could not read loader");
+
+    let output = MemFdExecutable::new("echo", &echo_contents)
+        .loader(&loader_contents)
+        .args(["hello", "world"])
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run echo");
+    assert_eq!(output.stdout, b"hello world\n");
+
+    // Bundled libraries are preloaded by the loader that was set
+    let (program, library) = build_greet();
+    let output = MemFdExecutable::new("greet", &program)
+        .loader(&loader_contents)
+        .library("libgreet.so.1", &library)
+        .arg("world")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run greet");
+    assert_eq!(output.stdout, b"hello world\n");
+}
+
+#[test]
+fn test_loader_invalid() {
+    let echo_contents = read("/bin/echo").expect("Could not read /bin/echo");
+
+    let err = MemFdExecutable::new("echo", &echo_contents)
+        .loader("/nonexistent/ld.so")
+        .spawn()
+        .expect_err("Ran echo with a missing loader");
+    let err = err.get_ref().unwrap().downcast_ref::<SpawnError>().unwrap();
+    assert_eq!(err.stage(), ExecStage::Loader);
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+    let err = MemFdExecutable::new("echo", &echo_contents)
+        .loader(b"not a loader")
+        .spawn()
+        .expect_err("Ran echo with a garbage loader");
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    let err = MemFdExecutable::new("greet", GREET_SCRIPT)
+        .loader("/nonexistent/ld.so")
+        .spawn()
+        .expect_err("Ran a script with a loader");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+/// A reader that fails with `ErrorKind::ConnectionReset` after reading `data`
+struct FailingReader<'a> {
+    data: &'a [u8],
+}
+
+impl Read for FailingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.data.is_empty() {
+            return Err(Error::from(ErrorKind::ConnectionReset));
+        }
+        self.data.read(buf)
+    }
+}
+
+#[test]
+fn test_from_reader() {
+    let echo_contents = read("/bin/echo").expect("Could not read /bin/echo");
+
+    let image = MemFdImage::from_reader(&echo_contents[..]).expect("Failed to create image");
+    assert_eq!(image.len(), echo_contents.len());
+    let output = MemFdExecutable::from_reader("echo", File::open("/bin/echo").unwrap())
+        .expect("Failed to create executable")
+        .arg("hello")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run echo");
+    assert_eq!(output.stdout, b"hello\n");
+
+    // Only the given length is read
+    let mut reader = &echo_contents[..];
+    let image = MemFdImage::from_reader_exact(&mut reader, echo_contents.len() as u64 - 100)
+        .expect("Failed to create image");
+    assert_eq!(image.len(), echo_contents.len() - 100);
+    assert_eq!(reader.len(), 100);
+}
+
+#[test]
+fn test_from_reader_truncated() {
+    let echo_contents = read("/bin/echo").expect("Could not read /bin/echo");
+    let half = echo_contents.len() / 2;
+
+    let err = MemFdImage::from_reader(FailingReader {
+        data: &echo_contents[..half],
+    })
+    .expect_err("Read from a failing reader");
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    assert!(
+        err.to_string().contains(&format!("after {half} bytes")),
+        "{err}"
+    );
+
+    let err = MemFdImage::from_reader_exact(&echo_contents[..half], echo_contents.len() as u64)
+        .expect_err("Read a truncated executable");
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    assert!(
+        err.to_string()
+            .contains(&format!("{half} of {} bytes", echo_contents.len())),
+        "{err}"
+    );
+
+    // Without a length, the truncated code is caught when it is spawned
+    let err = MemFdExecutable::from_reader("echo", &echo_contents[..half])
+        .expect("Failed to create executable")
+        .spawn()
+        .expect_err("Spawned a truncated executable");
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+/// An executable that owns its code, kept in a struct
+struct Greeter {
+    echo: MemFdExecutable<'static>,
+}
+
+impl Greeter {
+    fn new() -> Self {
+        let mut echo = MemFdExecutable::new("echo", read("/bin/echo").unwrap());
+        echo.arg("hello").stdout(Stdio::piped());
+        Self { echo }
+    }
+}
+
+#[test]
+fn test_owned_code() {
+    let mut greeter = Greeter::new();
+    let output = spawn(move || greeter.echo.output().expect("Failed to run echo"))
+        .join()
+        .unwrap();
+    assert_eq!(output.stdout, b"hello\n");
+
+    // Shared code is shared with the executables, not copied
+    let echo_contents: Arc<[u8]> = read("/bin/echo").unwrap().into();
+    for name in ["one", "two"] {
+        let output = MemFdExecutable::new("echo", echo_contents.clone())
+            .arg(name)
+            .stdout(Stdio::piped())
+            .output()
+            .expect("Failed to run echo");
+        assert_eq!(output.stdout, format!("{name}\n").as_bytes());
+    }
+    assert_eq!(Arc::strong_count(&echo_contents), 1);
+
+    let code = Code::from(Box::<[u8]>::from(&b"#!/bin/sh\necho hello"[..]));
+    assert!(matches!(code, Code::Owned(_)));
+    assert_eq!(format!("{code:?}"), "Owned([20 bytes])");
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn test_bytes_code() {
+    let echo_contents = bytes::Bytes::from(read("/bin/echo").unwrap());
+    let output = MemFdExecutable::new("echo", echo_contents)
+        .arg("hello")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run echo");
+    assert_eq!(output.stdout, b"hello\n");
+}
+
+#[test]
+fn test_from_file() {
+    let echo_contents = read("/bin/echo").expect("Could not read /bin/echo");
+    let image = MemFdImage::from_file("/bin/echo").expect("Failed to create image");
+    assert_eq!(image.len(), echo_contents.len());
+
+    // The whole file is copied, wherever its offset is, and the offset isn't moved
+    let mut file = File::open("/bin/echo").unwrap();
+    file.seek(SeekFrom::Start(100)).unwrap();
+    let output = MemFdExecutable::from_fd("echo", &file)
+        .expect("Failed to create executable")
+        .arg("hello")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run echo");
+    assert_eq!(output.stdout, b"hello\n");
+    assert_eq!(file.stream_position().unwrap(), 100);
+
+    // Between memfds, and from a temp file that may be on another filesystem
+    let copy = MemFdImage::from_fd(&image).expect("Failed to copy image");
+    assert_eq!(copy.len(), echo_contents.len());
+    let mut temp = tempfile().unwrap();
+    temp.write_all(&echo_contents).unwrap();
+    let copy = MemFdImage::from_fd(&temp).expect("Failed to copy temp file");
+    assert_eq!(copy.len(), echo_contents.len());
+    let output = MemFdExecutable::from_image("echo", &copy)
+        .arg("copy")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run echo");
+    assert_eq!(output.stdout, b"copy\n");
+}
+
+#[test]
+fn test_from_fd_socket() {
+    let echo_contents = read("/bin/echo").expect("Could not read /bin/echo");
+    let (ours, theirs) = UnixStream::pair().unwrap();
+    let writer = spawn(move || {
+        let mut ours = ours;
+        ours.write_all(&echo_contents).unwrap();
+        echo_contents.len()
+    });
+
+    let image = MemFdImage::from_fd(&theirs).expect("Failed to create image");
+    assert_eq!(image.len(), writer.join().unwrap());
+
+    let err = MemFdImage::from_file("/nonexistent").expect_err("Copied a missing file");
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+}
+
+/// A script that tries to append to itself, through the memfd it's run from
+const APPEND_SCRIPT: &[u8] =
+    b"#!/bin/sh\n(echo '#' >> \"$0\") 2>/dev/null && echo writable || echo sealed\n";
+
+fn run_append_script(seals: Option<Seals>) -> Vec<u8> {
+    let mut exe = MemFdExecutable::new("append", APPEND_SCRIPT);
+    if let Some(seals) = seals {
+        exe.seals(seals);
+    }
+    exe.stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run script")
+        .stdout
+}
+
+#[test]
+fn test_seals() {
+    assert_eq!(run_append_script(None), b"sealed\n");
+    assert_eq!(run_append_script(Some(Seals::empty())), b"writable\n");
+    assert_eq!(run_append_script(Some(Seals::SHRINK)), b"writable\n");
+    assert_eq!(run_append_script(Some(Seals::GROW)), b"sealed\n");
+
+    let seals = Seals::default();
+    assert!(seals.contains(Seals::WRITE | Seals::GROW | Seals::SHRINK | Seals::SEAL));
+    assert!(!seals.contains(Seals::FUTURE_WRITE));
+    assert_eq!(
+        seals.bits(),
+        libc::F_SEAL_WRITE | libc::F_SEAL_GROW | libc::F_SEAL_SHRINK | libc::F_SEAL_SEAL
+    );
+}
+
+/// Set when `test_memfd_noexec` reruns itself in a pid namespace with vm.memfd_noexec = 2
+const MEMFD_NOEXEC_ENV: &str = "MEMFD_EXEC_TEST_NOEXEC";
+
+#[test]
+#[serial]
+fn test_memfd_noexec() {
+    if var_os(MEMFD_NOEXEC_ENV).is_none() {
+        if unsafe { libc::geteuid() } != 0 {
+            // Setting the sysctl needs root
+            return;
+        }
+        // vm.memfd_noexec can't be lowered once it is raised, so only raise it in a new pid
+        // namespace. Kernels before 6.3 don't have it, and exit with 77.
+        let status = Command::new("unshare")
+            .args(["--pid", "--fork", "sh", "-c"])
+            .arg(
+                "echo 2 2>/dev/null >/proc/sys/vm/memfd_noexec || exit 77; \
+                 exec \"$0\" --exact test_memfd_noexec",
+            )
+            .arg(current_exe().expect("Could not get the test executable"))
+            .env(MEMFD_NOEXEC_ENV, "1")
+            .status();
+        match status {
+            Ok(status) if status.code() == Some(77) => {}
+            Ok(status) => assert!(status.success(), "test failed with vm.memfd_noexec = 2"),
+            // No unshare to run the test with
+            Err(_) => {}
+        }
+        return;
+    }
+
+    let true_contents = read("/bin/true").expect("Could not read /bin/true");
+    let check = |err: Error| {
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        let forbidden = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<MemfdExecForbidden>())
+            .expect("Error was not a MemfdExecForbidden");
+        assert_eq!(forbidden.memfd_noexec(), Some(2));
+        assert!(err.to_string().contains("vm.memfd_noexec"), "{err}");
+    };
+    check(
+        MemFdExecutable::new("true", &true_contents)
+            .spawn()
+            .expect_err("Executed a memfd with vm.memfd_noexec = 2"),
+    );
+    check(MemFdImage::new(&true_contents).expect_err("Created an image with vm.memfd_noexec = 2"));
+
+    // Code that is only read by an interpreter or mapped by a loader isn't executed itself
+    let output = MemFdExecutable::new("script", b"echo hello \"$1\"")
+        .interpreter("/bin/sh", [] as [&str; 0])
+        .arg("script")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run script with vm.memfd_noexec = 2");
+    assert_eq!(output.stdout, b"hello script\n");
+
+    // A `#!` script's memfd isn't executed either, its interpreter is
+    let output = MemFdExecutable::new("greet", GREET_SCRIPT)
+        .arg("shebang")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run #! script with vm.memfd_noexec = 2");
+    assert_eq!(output.stdout, b"hello shebang\n");
+
+    let image = MemFdImage::new(GREET_SCRIPT).expect("Failed to create script image");
+    let output = MemFdExecutable::from_image("greet", &image)
+        .arg("image")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run #! script image with vm.memfd_noexec = 2");
+    assert_eq!(output.stdout, b"hello image\n");
+
+    let (echo_contents, loader) = echo_with_missing_loader();
+    let output = MemFdExecutable::new("echo", &echo_contents)
+        .loader(loader.as_path())
+        .arg("hello")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run echo with vm.memfd_noexec = 2");
+    assert_eq!(output.stdout, b"hello\n");
+}
+
 #[test]
 #[serial]
 fn test_static_included() {
@@ -134,6 +1844,13 @@ fn test_static_included() {
     let test_static_exe = PathBuf::from(CARGO_TARGET_TMPDIR).join("test_static.bin");
     let test_static_exe_contents = read(test_static_exe).expect("Could not read static exe");
 
+    assert!(
+        MemFdExecutable::new("test_static.bin", &test_static_exe_contents)
+            .inspect()
+            .expect("Failed to inspect test_static")
+            .is_static()
+    );
+
     let test_static = MemFdExecutable::new("test_static.bin", &test_static_exe_contents)
         .stdout(Stdio::piped())
         .spawn()
@@ -0,0 +1,100 @@
+//! Test running compressed executables
+
+use std::{fs::read, io::ErrorKind};
+
+use memfd_exec::{MemFdExecutable, MemFdImage, Stdio};
+
+/// Run the echo in `image` and get what it prints
+fn run_echo(image: &MemFdImage) -> Vec<u8> {
+    MemFdExecutable::from_image("echo", image)
+        .arg("hello")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run echo")
+        .stdout
+}
+
+fn echo_contents() -> Vec<u8> {
+    read("/bin/echo").expect("Could not read /bin/echo")
+}
+
+#[test]
+fn test_uncompressed() {
+    let echo_contents = echo_contents();
+    let image = MemFdImage::from_compressed(&echo_contents).expect("Failed to create image");
+    assert_eq!(image.len(), echo_contents.len());
+    assert_eq!(run_echo(&image), b"hello\n");
+
+    let output = MemFdExecutable::from_compressed("greet", b"#!/bin/sh\necho hello $1\n")
+        .expect("Failed to create executable")
+        .arg("script")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run script");
+    assert_eq!(output.stdout, b"hello script\n");
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_zstd() {
+    let echo_contents = echo_contents();
+    let compressed = zstd::encode_all(&echo_contents[..], 19).expect("Failed to compress");
+    let image = MemFdImage::from_compressed(&compressed).expect("Failed to create image");
+    assert_eq!(image.len(), echo_contents.len());
+    assert_eq!(run_echo(&image), b"hello\n");
+
+    let err = MemFdImage::from_compressed(&compressed[..compressed.len() / 2])
+        .expect_err("Decompressed truncated code");
+    assert_ne!(err.kind(), ErrorKind::Unsupported);
+}
+
+#[cfg(feature = "xz")]
+#[test]
+fn test_xz() {
+    use std::io::Write;
+
+    let echo_contents = echo_contents();
+    let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+    encoder.write_all(&echo_contents).unwrap();
+    let compressed = encoder.finish().expect("Failed to compress");
+    let image = MemFdImage::from_compressed(&compressed).expect("Failed to create image");
+    assert_eq!(image.len(), echo_contents.len());
+    assert_eq!(run_echo(&image), b"hello\n");
+
+    MemFdImage::from_compressed(&compressed[..compressed.len() / 2])
+        .expect_err("Decompressed truncated code");
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn test_gzip() {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    let echo_contents = echo_contents();
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&echo_contents).unwrap();
+    let compressed = encoder.finish().expect("Failed to compress");
+    let mut exe =
+        MemFdExecutable::from_compressed("echo", &compressed).expect("Failed to create executable");
+    let output = exe
+        .arg("hello")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run echo");
+    assert_eq!(output.stdout, b"hello\n");
+
+    let err = MemFdImage::from_compressed(&compressed[..compressed.len() / 2])
+        .expect_err("Decompressed truncated code");
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[cfg(not(feature = "zstd"))]
+#[test]
+fn test_format_not_enabled() {
+    let err = MemFdImage::from_compressed(b"\x28\xb5\x2f\xfdcompressed")
+        .expect_err("Decompressed zstd without the feature");
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+    assert!(err.to_string().contains("`zstd` feature"), "{err}");
+}
@@ -0,0 +1,92 @@
+//! Test the async child process interface
+#![cfg(feature = "tokio")]
+
+use std::{fs::read, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+
+use memfd_exec::{MemFdExecutable, Stdio};
+
+#[tokio::test]
+async fn test_async_cat() {
+    let cat_contents = read("/bin/cat").expect("Could not read /bin/cat");
+    let mut cat = MemFdExecutable::new("cat", &cat_contents)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn_async()
+        .expect("Failed to run cat");
+
+    let mut stdin = cat.stdin.take().expect("Failed to open stdin");
+    stdin
+        .write_all(b"Hello, world!")
+        .await
+        .expect("Failed to write to cat stdin");
+    drop(stdin);
+
+    let output = cat.wait_with_output().await.expect("Failed to run cat");
+    assert_eq!(output.stdout, b"Hello, world!");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[tokio::test]
+async fn test_async_stdout_stderr() {
+    let sh_contents = read("/bin/sh").expect("Could not read /bin/sh");
+    let output = MemFdExecutable::new("sh", &sh_contents)
+        .arg("-c")
+        .arg("echo out; echo err >&2; exit 3")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn_async()
+        .expect("Failed to run sh")
+        .wait_with_output()
+        .await
+        .expect("Failed to run sh");
+
+    assert_eq!(output.stdout, b"out\n");
+    assert_eq!(output.stderr, b"err\n");
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[tokio::test]
+async fn test_async_wait_does_not_block() {
+    let sleep_contents = read("/bin/sleep").expect("Could not read /bin/sleep");
+    let mut sleep = MemFdExecutable::new("sleep", &sleep_contents)
+        .arg("10")
+        .spawn_async()
+        .expect("Failed to run sleep");
+
+    // The child is still running, so waiting on it must yield back to the runtime
+    assert!(timeout(Duration::from_millis(100), sleep.wait())
+        .await
+        .is_err());
+
+    sleep.kill().expect("Failed to kill sleep");
+    let status = timeout(Duration::from_secs(5), sleep.wait())
+        .await
+        .expect("Timed out waiting for sleep")
+        .expect("Failed to wait for sleep");
+    assert_eq!(status.signal(), Some(libc::SIGKILL));
+}
+
+#[tokio::test]
+async fn test_async_read_stdout() {
+    let echo_contents = read("/bin/echo").expect("Could not read /bin/echo");
+    let mut echo = MemFdExecutable::new("echo", &echo_contents)
+        .arg("hello")
+        .stdout(Stdio::piped())
+        .spawn_async()
+        .expect("Failed to run echo");
+
+    let mut stdout = Vec::new();
+    echo.stdout
+        .take()
+        .expect("Failed to open stdout")
+        .read_to_end(&mut stdout)
+        .await
+        .expect("Failed to read from echo stdout");
+    assert_eq!(stdout, b"hello\n");
+    assert_eq!(echo.wait().await.expect("Failed to wait").code(), Some(0));
+}